@@ -7,7 +7,7 @@ use krun_guest::cli_options::options;
 use krun_guest::fex::setup_fex;
 use krun_guest::mount::mount_filesystems;
 use krun_guest::net::configure_network;
-use krun_guest::socket::setup_socket_proxy;
+use krun_guest::socket::{setup_forwarded_sockets, setup_socket_proxy};
 use krun_guest::sommelier::exec_sommelier;
 use krun_guest::user::setup_user;
 use krun_guest::x11::setup_x11_forwarding;
@@ -59,7 +59,9 @@ fn main() -> Result<()> {
     let pulse_path = pulse_path.join("native");
     setup_socket_proxy(pulse_path, 3333)?;
 
-    setup_x11_forwarding(run_path)?;
+    setup_x11_forwarding(&run_path)?;
+
+    setup_forwarded_sockets(&run_path)?;
 
     // Will not return if successful.
     exec_sommelier(&options.command, &options.command_args)
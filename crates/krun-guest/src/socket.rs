@@ -1,33 +1,37 @@
 use std::collections::HashMap;
 use std::env;
-use std::path::Path;
+use std::fs::DirBuilder;
+use std::os::unix::fs::DirBuilderExt as _;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
-use anyhow::{Context, Result};
-use utils::env::find_in_path;
+use anyhow::{anyhow, Context, Result};
+use log::trace;
+use utils::env::{find_in_path, parse_forwarded_sockets, FORWARDED_SOCKETS_ENV_VAR};
+use utils::fs::find_executable;
 use utils::stdio::make_stdout_stderr;
 
+/// Context id of the host, which the microVM reaches over vsock unless
+/// overridden with `KRUN_VSOCK_CID`.
+const HOST_VSOCK_CID: u32 = 2;
+
 pub fn setup_socket_proxy<P>(socket_path: P, port: u16) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    let socat_path = find_in_path("socat").context("Failed to check existence of `socat`")?;
-    let Some(socat_path) = socat_path else {
+    let Some(socat_path) = socat_path()? else {
         return Ok(());
     };
+    let cid = vsock_cid()?;
+    create_socket_dir(socket_path.as_ref())?;
 
     let envs: HashMap<String, String> = env::vars().collect();
     let (stdout, stderr) = make_stdout_stderr(&socat_path, &envs)?;
 
+    let args = socat_args(socket_path.as_ref(), cid, port);
+    trace!(socat_path:?, args:?; "starting socket proxy");
     Command::new(socat_path)
-        .arg(format!(
-            "UNIX-LISTEN:{},fork",
-            socket_path
-                .as_ref()
-                .to_str()
-                .expect("socket_path should not contain invalid UTF-8")
-        ))
-        .arg(format!("VSOCK-CONNECT:2:{}", port))
+        .args(args)
         .stdin(Stdio::null())
         .stdout(stdout)
         .stderr(stderr)
@@ -36,3 +40,163 @@ where
 
     Ok(())
 }
+
+/// Proxies the host sockets forwarded with `--forward-socket`, as listed in
+/// `KRUN_FORWARDED_SOCKETS`, to sockets in `run_path`, and points the env var
+/// of each at the guest side.
+pub fn setup_forwarded_sockets<P>(run_path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let Ok(sockets) = env::var(FORWARDED_SOCKETS_ENV_VAR) else {
+        return Ok(());
+    };
+    for (env_var, port) in parse_forwarded_sockets(&sockets)? {
+        let port = u16::try_from(port)
+            .with_context(|| format!("Invalid vsock port {port} for `{env_var}`"))?;
+        let socket_path = forwarded_socket_path(run_path.as_ref(), &env_var);
+        setup_socket_proxy(&socket_path, port)?;
+        // SAFETY: Safe if and only if `krun-guest` program is not multithreaded.
+        // See https://doc.rust-lang.org/std/env/fn.set_var.html#safety
+        env::set_var(env_var, socket_path);
+    }
+
+    Ok(())
+}
+
+fn forwarded_socket_path(run_path: &Path, env_var: &str) -> PathBuf {
+    run_path.join("krun/socket").join(env_var)
+}
+
+/// Creates the directory of `socket_path` if it's missing, only accessible to
+/// us, as socat fails to listen on the socket otherwise. Other processes may
+/// be creating it at the same time, which is fine.
+fn create_socket_dir(socket_path: &Path) -> Result<()> {
+    let Some(dir) = socket_path.parent() else {
+        return Ok(());
+    };
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Failed to create socket directory {dir:?}"))
+}
+
+/// Returns the socat addresses to proxy connections to `socket_path` to `port`
+/// of the vsock context `cid`.
+fn socat_args(socket_path: &Path, cid: u32, port: u16) -> [String; 2] {
+    [
+        format!(
+            "UNIX-LISTEN:{},fork",
+            socket_path
+                .to_str()
+                .expect("socket_path should not contain invalid UTF-8")
+        ),
+        format!("VSOCK-CONNECT:{cid}:{port}"),
+    ]
+}
+
+/// Returns the vsock context id to connect to: `KRUN_VSOCK_CID` if set, which
+/// krun forwards from the host, otherwise the host.
+fn vsock_cid() -> Result<u32> {
+    match env::var("KRUN_VSOCK_CID") {
+        Ok(cid) => parse_vsock_cid(&cid),
+        Err(_) => Ok(HOST_VSOCK_CID),
+    }
+}
+
+fn parse_vsock_cid(cid: &str) -> Result<u32> {
+    let cid: u32 = cid
+        .parse()
+        .with_context(|| format!("Failed to parse `KRUN_VSOCK_CID` value {cid:?}"))?;
+    // 0 is reserved for the hypervisor, and `u32::MAX` is `VMADDR_CID_ANY`,
+    // which can only be listened on.
+    if cid == 0 || cid == u32::MAX {
+        return Err(anyhow!(
+            "`KRUN_VSOCK_CID` {cid} is not a vsock context id to connect to"
+        ));
+    }
+    Ok(cid)
+}
+
+/// Returns the socat executable: `KRUN_SOCAT` if set, which krun forwards from
+/// the host and must be executable, otherwise `socat` if found in `PATH`.
+fn socat_path() -> Result<Option<PathBuf>> {
+    let Some(socat_path) = env::var_os("KRUN_SOCAT") else {
+        return find_in_path("socat").context("Failed to check existence of `socat`");
+    };
+    let socat_path = PathBuf::from(socat_path);
+    // Run it as given rather than resolved, in case it is a symlink to a
+    // wrapper that looks at its name.
+    find_executable(&socat_path)
+        .with_context(|| format!("Failed to check existence of {socat_path:?}"))?
+        .with_context(|| format!("`KRUN_SOCAT` {socat_path:?} is not an executable file"))?;
+
+    Ok(Some(socat_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::*;
+
+    #[test]
+    fn socat_args_with_cid() {
+        let socket_path = Path::new("/run/pulse/native");
+        assert_eq!(
+            socat_args(socket_path, HOST_VSOCK_CID, 3333),
+            ["UNIX-LISTEN:/run/pulse/native,fork", "VSOCK-CONNECT:2:3333"]
+        );
+        assert_eq!(
+            socat_args(socket_path, parse_vsock_cid("42").unwrap(), 3333)[1],
+            "VSOCK-CONNECT:42:3333"
+        );
+        for cid in ["", "host", "-1", "0", "4294967295", "4294967296"] {
+            assert!(parse_vsock_cid(cid).is_err(), "{cid:?}");
+        }
+    }
+
+    #[test]
+    fn forwarded_ssh_agent_socket_path() {
+        assert_eq!(
+            forwarded_socket_path(Path::new("/run/user/1000"), "SSH_AUTH_SOCK"),
+            Path::new("/run/user/1000/krun/socket/SSH_AUTH_SOCK")
+        );
+    }
+
+    #[test]
+    fn recreate_socket_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_dir = dir.path().join("krun/socket");
+        let socket_path = socket_dir.join("port-3333");
+
+        create_socket_dir(&socket_path).unwrap();
+        let mode = fs::metadata(&socket_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        fs::remove_dir(&socket_dir).unwrap();
+        create_socket_dir(&socket_path).unwrap();
+        assert!(socket_dir.is_dir());
+        // Already there, as when created concurrently.
+        create_socket_dir(&socket_path).unwrap();
+    }
+
+    #[test]
+    fn socat_path_from_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let fake_socat = dir.path().join("fake-socat");
+        fs::write(&fake_socat, "#!/bin/sh\n").unwrap();
+
+        env::set_var("KRUN_SOCAT", &fake_socat);
+        assert!(socat_path().is_err());
+        fs::set_permissions(&fake_socat, Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(socat_path().unwrap(), Some(fake_socat));
+
+        env::set_var("KRUN_SOCAT", dir.path().join("missing"));
+        let err = socat_path().unwrap_err();
+        env::remove_var("KRUN_SOCAT");
+        assert!(err.to_string().contains("is not an executable file"));
+    }
+}
@@ -19,8 +19,9 @@ async fn main() -> Result<()> {
     let listener = TcpListener::bind(format!("0.0.0.0:{}", options.server_port)).await?;
     let (state_tx, state_rx) = watch::channel(State::new());
 
+    let max_launches = options.max_launches;
     let mut server_handle = tokio::spawn(async move {
-        let mut server = Server::new(listener, state_tx);
+        let mut server = Server::new(listener, state_tx, max_launches);
         server.run().await;
     });
     let command_status = Command::new(&options.command)
@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Context, Result};
+use rustix::thread::{
+    capabilities, remove_capability_from_bounding_set, set_capabilities, Capability,
+    CapabilityFlags,
+};
+use utils::launch::Capabilities;
+
+/// Every capability known to Linux, by number.
+const CAPABILITIES: [(&str, Capability); 41] = [
+    ("CHOWN", Capability::ChangeOwnership),
+    ("DAC_OVERRIDE", Capability::DACOverride),
+    ("DAC_READ_SEARCH", Capability::DACReadSearch),
+    ("FOWNER", Capability::FileOwner),
+    ("FSETID", Capability::FileSetID),
+    ("KILL", Capability::Kill),
+    ("SETGID", Capability::SetGroupID),
+    ("SETUID", Capability::SetUserID),
+    ("SETPCAP", Capability::SetPermittedCapabilities),
+    ("LINUX_IMMUTABLE", Capability::LinuxImmutable),
+    ("NET_BIND_SERVICE", Capability::NetBindService),
+    ("NET_BROADCAST", Capability::NetBroadcast),
+    ("NET_ADMIN", Capability::NetAdmin),
+    ("NET_RAW", Capability::NetRaw),
+    ("IPC_LOCK", Capability::IPCLock),
+    ("IPC_OWNER", Capability::IPCOwner),
+    ("SYS_MODULE", Capability::SystemModule),
+    ("SYS_RAWIO", Capability::SystemRawIO),
+    ("SYS_CHROOT", Capability::SystemChangeRoot),
+    ("SYS_PTRACE", Capability::SystemProcessTrace),
+    ("SYS_PACCT", Capability::SystemProcessAccounting),
+    ("SYS_ADMIN", Capability::SystemAdmin),
+    ("SYS_BOOT", Capability::SystemBoot),
+    ("SYS_NICE", Capability::SystemNice),
+    ("SYS_RESOURCE", Capability::SystemResource),
+    ("SYS_TIME", Capability::SystemTime),
+    ("SYS_TTY_CONFIG", Capability::SystemTTYConfig),
+    ("MKNOD", Capability::MakeNode),
+    ("LEASE", Capability::Lease),
+    ("AUDIT_WRITE", Capability::AuditWrite),
+    ("AUDIT_CONTROL", Capability::AuditControl),
+    ("SETFCAP", Capability::SetFileCapabilities),
+    ("MAC_OVERRIDE", Capability::MACOverride),
+    ("MAC_ADMIN", Capability::MACAdmin),
+    ("SYSLOG", Capability::SystemLog),
+    ("WAKE_ALARM", Capability::WakeAlarm),
+    ("BLOCK_SUSPEND", Capability::BlockSuspend),
+    ("AUDIT_READ", Capability::AuditRead),
+    ("PERFMON", Capability::PerformanceMonitoring),
+    ("BPF", Capability::BerkeleyPacketFilters),
+    ("CHECKPOINT_RESTORE", Capability::CheckpointRestore),
+];
+
+/// Capabilities to take away from a child process.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct CapabilityDrop {
+    dropped: Vec<Capability>,
+}
+
+impl CapabilityDrop {
+    /// Resolves the capabilities to drop for `spec`, failing on unknown names
+    /// or if we aren't allowed to drop them from the bounding set.
+    pub fn new(spec: &Capabilities) -> Result<Self> {
+        let caps_drop = Self::resolve(spec)?;
+        let sets = capabilities(None).context("Failed to get the capabilities of krun-server")?;
+        if !sets.effective.contains(CapabilityFlags::SETPCAP) {
+            return Err(anyhow!(
+                "krun-server does not have CAP_SETPCAP, so it can't restrict the capabilities \
+                 of commands"
+            ));
+        }
+        Ok(caps_drop)
+    }
+
+    fn resolve(spec: &Capabilities) -> Result<Self> {
+        let dropped = match spec {
+            Capabilities::Drop(names) => names
+                .iter()
+                .map(|name| parse_capability(name))
+                .collect::<Result<_>>()?,
+            Capabilities::Keep(names) => {
+                let kept = names
+                    .iter()
+                    .map(|name| parse_capability(name))
+                    .collect::<Result<Vec<_>>>()?;
+                CAPABILITIES
+                    .iter()
+                    .map(|&(_, cap)| cap)
+                    .filter(|cap| !kept.contains(cap))
+                    .collect()
+            },
+        };
+        Ok(Self { dropped })
+    }
+
+    /// Takes the capabilities away from the bounding set. To be done before
+    /// switching users, which takes away the privilege to do so. Only makes
+    /// system calls, so that it can be used between fork and exec.
+    pub fn apply_to_bounding_set(&self) -> rustix::io::Result<()> {
+        for &cap in &self.dropped {
+            remove_capability_from_bounding_set(cap)?;
+        }
+        Ok(())
+    }
+
+    /// Takes the capabilities away from the effective, permitted and
+    /// inheritable sets, which also takes them away from the ambient set. Only
+    /// makes system calls, so that it can be used between fork and exec.
+    pub fn apply(&self) -> rustix::io::Result<()> {
+        let flags = self
+            .dropped
+            .iter()
+            .fold(CapabilityFlags::empty(), |flags, &cap| {
+                flags | CapabilityFlags::from_bits_retain(1 << cap as u32)
+            });
+        let mut sets = capabilities(None)?;
+        sets.effective.remove(flags);
+        sets.permitted.remove(flags);
+        sets.inheritable.remove(flags);
+        set_capabilities(None, sets)
+    }
+}
+
+/// Looks up a capability by name, case-insensitively and with or without the
+/// `CAP_` prefix.
+fn parse_capability(name: &str) -> Result<Capability> {
+    let upper = name.to_ascii_uppercase();
+    let bare = upper.strip_prefix("CAP_").unwrap_or(&upper);
+    CAPABILITIES
+        .iter()
+        .find(|&&(known, _)| known == bare)
+        .map(|&(_, cap)| cap)
+        .ok_or_else(|| anyhow!("Unknown capability {name:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_capability_names() {
+        assert_eq!(parse_capability("NET_ADMIN").unwrap(), Capability::NetAdmin);
+        assert_eq!(
+            parse_capability("cap_sys_admin").unwrap(),
+            Capability::SystemAdmin
+        );
+        let err = parse_capability("NET_MAGIC").unwrap_err();
+        assert_eq!(err.to_string(), r#"Unknown capability "NET_MAGIC""#);
+        for (number, &(_, cap)) in CAPABILITIES.iter().enumerate() {
+            assert_eq!(cap as usize, number);
+        }
+    }
+
+    #[test]
+    fn keep_or_drop_capabilities() {
+        let spec = Capabilities::Drop(vec!["NET_ADMIN".to_owned(), "SYS_ADMIN".to_owned()]);
+        assert_eq!(
+            CapabilityDrop::resolve(&spec).unwrap().dropped,
+            [Capability::NetAdmin, Capability::SystemAdmin]
+        );
+
+        // A keep list drops everything else.
+        let spec = Capabilities::Keep(vec!["NET_BIND_SERVICE".to_owned()]);
+        let dropped = CapabilityDrop::resolve(&spec).unwrap().dropped;
+        assert_eq!(dropped.len(), CAPABILITIES.len() - 1);
+        assert!(!dropped.contains(&Capability::NetBindService));
+        assert!(dropped.contains(&Capability::NetAdmin));
+
+        let spec = Capabilities::Keep(vec!["NET_MAGIC".to_owned()]);
+        assert!(CapabilityDrop::resolve(&spec).is_err());
+    }
+}
@@ -1,10 +1,11 @@
 use std::path::PathBuf;
 
-use bpaf::{any, construct, env, positional, OptionParser, Parser};
+use bpaf::{any, construct, env, long, positional, OptionParser, Parser};
 
 #[derive(Clone, Debug)]
 pub struct Options {
     pub server_port: u32,
+    pub max_launches: Option<usize>,
     pub command: PathBuf,
     pub command_args: Vec<String>,
 }
@@ -16,6 +17,12 @@ pub fn options() -> OptionParser<Options> {
         .argument("SERVER_PORT")
         .fallback(3334)
         .display_fallback();
+    let max_launches = long("max-launches")
+        .env("KRUN_MAX_LAUNCHES")
+        .help("Maximum number of commands to run at once, queueing further launch requests")
+        .argument::<usize>("N")
+        .guard(|&n| n > 0, "must be at least 1")
+        .optional();
     let command = positional("COMMAND");
     let command_args = any::<String, _, _>("COMMAND_ARGS", |arg| {
         (!["--help", "-h"].contains(&&*arg)).then_some(arg)
@@ -24,6 +31,7 @@ pub fn options() -> OptionParser<Options> {
 
     construct!(Options {
         server_port,
+        max_launches,
         // positionals
         command,
         command_args,
@@ -1,2 +1,4 @@
+pub mod caps;
 pub mod cli_options;
 pub mod server;
+pub mod user;
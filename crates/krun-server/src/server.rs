@@ -1,26 +1,61 @@
 use std::collections::HashMap;
-use std::os::unix::process::ExitStatusExt as _;
-use std::path::PathBuf;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::ffi::OsStringExt as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::os::unix::process::{CommandExt as _, ExitStatusExt as _};
+use std::path::{Path, PathBuf};
 use std::process::{ExitStatus, Stdio};
-use std::{env, io};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, fmt, future, io};
 
 use anyhow::{anyhow, Context, Result};
-use log::{debug, error};
-use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufStream};
+use log::{debug, error, trace};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{setsid, Pid};
+use rustix::fs::{open, Mode, OFlags};
+use rustix::stdio::{dup2_stderr, dup2_stdout};
+use tokio::io::{
+    split, AsyncBufReadExt as _, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _,
+    BufStream,
+};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::process::{Child, Command};
-use tokio::sync::watch;
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::{mpsc, watch, Notify, OwnedSemaphorePermit, Semaphore};
 use tokio::task::{JoinError, JoinSet};
+use tokio::time;
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_stream::StreamExt as _;
-use utils::launch::Launch;
-use utils::stdio::make_stdout_stderr;
+use utils::env::runtime_dir;
+use utils::launch::{
+    frame_header, parse_frame_header, BatchPolicy, BatchStatus, ErrorResponse, FrameKind, Launch,
+    LaunchInfo, Request, ServerInfo, ERROR_RESPONSE_PREFIX, FRAME_HEADER_LEN, MAX_REQUEST_LEN,
+    MAX_STDIN_FRAME_LEN, MAX_STDIN_LEN, PROTOCOL_VERSION,
+};
+
+use crate::caps::CapabilityDrop;
+use crate::user::TargetUser;
+
+/// How long a child process that timed out or is being killed has to exit
+/// after SIGTERM, before getting SIGKILL.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Env vars of the server that commands launched with a clean environment
+/// still get, if they are set.
+const CLEAN_ENV_VARS: [&str; 3] = ["PATH", "HOME", "TERM"];
 
 #[derive(Debug)]
 pub struct Server {
     listener_stream: TcpListenerStream,
+    started: Instant,
+    launches: Arc<Mutex<Launches>>,
+    /// Limits how many launches run at once, if at all.
+    launch_slots: Option<Arc<Semaphore>>,
     state_tx: watch::Sender<State>,
-    child_set: JoinSet<(PathBuf, ChildResult)>,
+    /// Also includes the launches waiting for a slot.
+    child_set: JoinSet<Result<(PathBuf, ChildResult)>>,
+    batch_set: JoinSet<Result<()>>,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
@@ -31,12 +66,68 @@ pub struct State {
 
 type ChildResult = Result<ExitStatus, io::Error>;
 
+/// Launches whose command is still running, so that clients can list and kill
+/// them.
+#[derive(Debug, Default)]
+struct Launches {
+    next_id: u64,
+    running: HashMap<u64, RunningLaunch>,
+}
+
+#[derive(Debug)]
+struct RunningLaunch {
+    info: LaunchInfo,
+    kill: Arc<Notify>,
+}
+
+/// A request that takes more than answering the client right away.
+// There's one per connection, as with `Request`.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+enum Accepted {
+    Launch(Launch),
+    Batch {
+        launches: Vec<Launch>,
+        policy: BatchPolicy,
+    },
+}
+
+/// A child process spawned for a launch request, along with the connection
+/// to the client that requested it.
+#[derive(Debug)]
+struct LaunchedChild {
+    id: u64,
+    command: PathBuf,
+    child: Child,
+    timeout: Option<Duration>,
+    kill: Arc<Notify>,
+    /// Whether the child process is killed once the client goes away.
+    kill_on_disconnect: bool,
+    /// How long the client may go without sending any frame, if it sends
+    /// heartbeats.
+    idle_timeout: Option<Duration>,
+    /// Whether the stderr of the command is sent to the client as stdout.
+    merge_stderr: bool,
+    /// `None` for detached launches.
+    stream: Option<BufStream<TcpStream>>,
+}
+
 impl Server {
-    pub fn new(listener: TcpListener, state_tx: watch::Sender<State>) -> Self {
+    /// Runs at most `max_launches` launches at once if given, queueing the
+    /// other ones.
+    pub fn new(
+        listener: TcpListener,
+        state_tx: watch::Sender<State>,
+        max_launches: Option<usize>,
+    ) -> Self {
         Server {
             listener_stream: TcpListenerStream::new(listener),
+            started: Instant::now(),
+            launches: Arc::default(),
+            launch_slots: max_launches.map(|max| Arc::new(Semaphore::new(max))),
             state_tx,
             child_set: JoinSet::new(),
+            batch_set: JoinSet::new(),
         }
     }
 
@@ -55,10 +146,28 @@ impl Server {
                     };
                     let stream = BufStream::new(stream);
 
-                    match handle_connection(stream).await {
-                        Ok((command, mut child)) => {
-                            self.child_set.spawn(async move { (command, child.wait().await) });
-                            self.set_child_processes(self.child_set.len());
+                    match handle_connection(stream, self.started, &self.launches).await {
+                        Ok(Some((Accepted::Launch(launch), stream))) => {
+                            self.child_set.spawn(run_launch(
+                                launch,
+                                stream,
+                                self.launches.clone(),
+                                self.launch_slots.clone(),
+                            ));
+                            self.update_child_processes();
+                        },
+                        Ok(Some((Accepted::Batch { launches, policy }, stream))) => {
+                            self.batch_set.spawn(run_batch(
+                                launches,
+                                policy,
+                                stream,
+                                self.launches.clone(),
+                                self.launch_slots.clone(),
+                            ));
+                            self.update_child_processes();
+                        },
+                        Ok(None) => {
+                            debug!("connection closed without launching anything");
                         },
                         Err(err) => {
                             eprintln!("Failed to process client request: {err:?}");
@@ -67,13 +176,21 @@ impl Server {
                     self.set_connection_idle(true);
                 },
                 Some(res) = self.child_set.join_next() => self.handle_child_join(res),
+                Some(res) = self.batch_set.join_next() => {
+                    match res {
+                        Ok(Ok(())) => {},
+                        Ok(Err(err)) => eprintln!("Failed to process batch request: {err:?}"),
+                        Err(err) => error!(err:% = err; "batch task failed"),
+                    }
+                    self.update_child_processes();
+                },
             }
         }
     }
 
-    fn handle_child_join(&self, res: Result<(PathBuf, ChildResult), JoinError>) {
+    fn handle_child_join(&self, res: Result<Result<(PathBuf, ChildResult)>, JoinError>) {
         match res {
-            Ok((command, res)) => match res {
+            Ok(Ok((command, res))) => match res {
                 Ok(status) => {
                     debug!(command:?; "child process exited");
                     if !status.success() {
@@ -93,11 +210,14 @@ impl Server {
                     eprintln!("Failed to wait for {command:?} process to exit: {err}");
                 },
             },
+            Ok(Err(err)) => {
+                eprintln!("Failed to process client request: {err:?}");
+            },
             Err(err) => {
                 error!(err:% = err; "child task failed");
             },
         }
-        self.set_child_processes(self.child_set.len());
+        self.update_child_processes();
     }
 
     fn set_connection_idle(&self, connection_idle: bool) {
@@ -110,6 +230,12 @@ impl Server {
         });
     }
 
+    /// Counts running batches as child processes too, as they run some or are
+    /// about to.
+    fn update_child_processes(&self) {
+        self.set_child_processes(self.child_set.len() + self.batch_set.len());
+    }
+
     fn set_child_processes(&self, child_processes: usize) {
         self.state_tx.send_if_modified(|state| {
             if state.child_processes == child_processes {
@@ -144,47 +270,1593 @@ impl Default for State {
     }
 }
 
-async fn read_request(stream: &mut BufStream<TcpStream>) -> Result<Launch> {
+impl Launches {
+    fn add(&mut self, command: &Path, command_args: &[String]) -> (u64, Arc<Notify>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let started_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let kill = Arc::new(Notify::new());
+        let info = LaunchInfo {
+            id,
+            command: command.to_owned(),
+            command_args: command_args.to_owned(),
+            started_secs,
+        };
+        self.running.insert(
+            id,
+            RunningLaunch {
+                info,
+                kill: kill.clone(),
+            },
+        );
+        (id, kill)
+    }
+
+    fn list(&self) -> Vec<LaunchInfo> {
+        let mut launches: Vec<_> = self.running.values().map(|l| l.info.clone()).collect();
+        launches.sort_by_key(|info| info.id);
+        launches
+    }
+
+    fn kill(&self, id: u64) -> Result<()> {
+        let launch = self
+            .running
+            .get(&id)
+            .with_context(|| format!("No running launch with id {id}"))?;
+        // Stores a permit if the child task isn't waiting yet, so the request
+        // isn't lost.
+        launch.kill.notify_one();
+        Ok(())
+    }
+}
+
+async fn read_request(stream: &mut BufStream<TcpStream>) -> Result<Option<Request>> {
+    let Some(&first) = stream.fill_buf().await?.first() else {
+        // e.g. a liveness check from krun
+        return Ok(None);
+    };
+
+    if first == FrameKind::Request as u8 {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        let (_, len) = parse_frame_header(&header);
+        if len > MAX_REQUEST_LEN {
+            return Err(anyhow!("request of {len} bytes is too large"));
+        }
+        let mut buf = vec![0u8; len as usize];
+        stream.read_exact(&mut buf).await?;
+        let request: Request = serde_json::from_slice(&buf)?;
+        return Ok(Some(request));
+    }
+
+    // Older clients send a launch request as JSON terminated by an `EOM` line.
     let mut buf = String::new();
     loop {
+        let len = buf.len();
         if stream.read_line(&mut buf).await? == 0 {
             return Err(anyhow!("unexpected EOF"));
         }
-        if buf.contains("EOM") {
-            let launch: Launch = serde_json::from_str(&buf[..buf.len() - 5])?;
-            return Ok(launch);
+        if buf[len..].trim_end() == "EOM" {
+            let launch: Launch = serde_json::from_str(&buf[..len])?;
+            return Ok(Some(Request::Launch(launch)));
+        }
+    }
+}
+
+/// Answers the request of a client, except for launch and batch requests
+/// which are returned to be run with [`run_launch`] and [`run_batch`].
+async fn handle_connection(
+    mut stream: BufStream<TcpStream>,
+    started: Instant,
+    launches: &Mutex<Launches>,
+) -> Result<Option<(Accepted, BufStream<TcpStream>)>> {
+    let launch = match read_request(&mut stream).await? {
+        Some(Request::Launch(launch)) => launch,
+        Some(Request::LaunchBatch { launches, policy }) => {
+            let summaries: Vec<_> = launches.iter().map(LaunchSummary).collect();
+            debug!(launches:? = summaries, policy:?; "received batch request");
+            return Ok(Some((Accepted::Batch { launches, policy }, stream)));
+        },
+        Some(Request::Ping) => {
+            debug!("received ping request");
+            let info = ServerInfo {
+                protocol_version: PROTOCOL_VERSION,
+                uptime_secs: started.elapsed().as_secs(),
+            };
+            let mut resp = serde_json::to_string(&info)?;
+            resp.push('\n');
+            stream.write_all(resp.as_bytes()).await?;
+            stream.flush().await?;
+            return Ok(None);
+        },
+        Some(Request::ListLaunches) => {
+            debug!("received list launches request");
+            let list = launches.lock().unwrap().list();
+            let mut resp = serde_json::to_string(&list)?;
+            resp.push('\n');
+            stream.write_all(resp.as_bytes()).await?;
+            stream.flush().await?;
+            return Ok(None);
+        },
+        Some(Request::KillLaunch { id }) => {
+            debug!(id; "received kill launch request");
+            let resp = match launches.lock().unwrap().kill(id) {
+                Ok(()) => "OK\n".to_owned(),
+                Err(err) => error_response(&err),
+            };
+            stream.write_all(resp.as_bytes()).await?;
+            stream.flush().await?;
+            return Ok(None);
+        },
+        None => return Ok(None),
+    };
+    debug!(launch:? = LaunchSummary(&launch); "received launch request");
+    Ok(Some((Accepted::Launch(launch), stream)))
+}
+
+/// Shows what a launch runs for logging, leaving out the values of its env
+/// vars, which may well be tokens or keys, and its stdin.
+struct LaunchSummary<'a>(&'a Launch);
+
+impl fmt::Debug for LaunchSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let launch = self.0;
+        let mut env_keys: Vec<_> = launch.env.keys().collect();
+        env_keys.sort();
+        f.debug_struct("Launch")
+            .field("command", &launch.command)
+            .field("command_args", &launch.command_args)
+            .field("env_keys", &env_keys)
+            .field("cwd", &launch.cwd)
+            .field("user", &launch.user)
+            .field("detach", &launch.detach)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Starts a launch once there's a free slot for it, and waits for its command
+/// to exit. The slot is taken until then.
+async fn run_launch(
+    launch: Launch,
+    mut stream: BufStream<TcpStream>,
+    launches: Arc<Mutex<Launches>>,
+    launch_slots: Option<Arc<Semaphore>>,
+) -> Result<(PathBuf, ChildResult)> {
+    let _slot = match launch_slots {
+        Some(slots) => Some(acquire_launch_slot(slots, launch.fail_if_busy, &mut stream).await?),
+        None => None,
+    };
+    let launched = start_launch(launch, stream, &launches).await?;
+    Ok(wait_for_child(launched, launches).await)
+}
+
+/// Takes a slot for a launch, telling the client if it has to wait for one or
+/// refusing the launch if it asked not to wait. A queued launch is dropped if
+/// the client goes away before a slot is free.
+async fn acquire_launch_slot(
+    slots: Arc<Semaphore>,
+    fail_if_busy: bool,
+    stream: &mut BufStream<TcpStream>,
+) -> Result<OwnedSemaphorePermit> {
+    if let Ok(slot) = slots.clone().try_acquire_owned() {
+        return Ok(slot);
+    }
+    if fail_if_busy {
+        let err = anyhow!("Too many launches are running already, try again later");
+        stream.write_all(error_response(&err).as_bytes()).await.ok();
+        stream.flush().await.ok();
+        return Err(err);
+    }
+
+    debug!("queueing launch until a slot is free");
+    stream.write_all(b"QUEUED\n").await?;
+    stream.flush().await?;
+    let client_gone = async {
+        // The client sends nothing until its launch starts, so anything but
+        // data it sent early, left for later, means the connection is gone.
+        let mut buf = [0u8; 1];
+        if let Ok(1..) = stream.get_ref().peek(&mut buf).await {
+            future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        slot = slots.acquire_owned() => slot.context("Launch slots are gone"),
+        () = client_gone => {
+            debug!("client went away while its launch was queued");
+            Err(anyhow!("Client went away while its launch was queued"))
+        },
+    }
+}
+
+/// Runs the commands of a batch one after the other, each once there's a free
+/// slot for it, and answers the client with how each went.
+async fn run_batch(
+    batch: Vec<Launch>,
+    policy: BatchPolicy,
+    mut stream: BufStream<TcpStream>,
+    launches: Arc<Mutex<Launches>>,
+    launch_slots: Option<Arc<Semaphore>>,
+) -> Result<()> {
+    let mut statuses = Vec::with_capacity(batch.len());
+    for launch in batch {
+        let stop = policy == BatchPolicy::StopOnFailure
+            && statuses
+                .last()
+                .is_some_and(|status: &BatchStatus| !status.success());
+        if stop {
+            statuses.push(BatchStatus::Skipped);
+            continue;
         }
+        let command = launch.command.clone();
+        let status = run_batch_launch(launch, &launches, launch_slots.clone()).await;
+        debug!(command:?, status:?; "batch command done");
+        statuses.push(status);
+    }
+
+    let mut resp = serde_json::to_string(&statuses)?;
+    resp.push('\n');
+    stream.write_all(resp.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Runs a command of a batch as a detached launch, so that it can be listed
+/// and killed as any other.
+async fn run_batch_launch(
+    launch: Launch,
+    launches: &Mutex<Launches>,
+    launch_slots: Option<Arc<Semaphore>>,
+) -> BatchStatus {
+    let _slot = match launch_slots {
+        Some(slots) if launch.fail_if_busy => match slots.try_acquire_owned() {
+            Ok(slot) => Some(slot),
+            Err(_) => {
+                return BatchStatus::Failed {
+                    error: "Too many launches are running already".to_owned(),
+                };
+            },
+        },
+        Some(slots) => match slots.acquire_owned().await {
+            Ok(slot) => Some(slot),
+            Err(err) => {
+                return BatchStatus::Failed {
+                    error: format!("Launch slots are gone: {err}"),
+                };
+            },
+        },
+        None => None,
+    };
+
+    let (id, kill) = launches
+        .lock()
+        .unwrap()
+        .add(&launch.command, &launch.command_args);
+    let command = launch.command.clone();
+    let timeout = launch.timeout_ms.map(Duration::from_millis);
+    let res = detached_log(true, id).and_then(|log| spawn_child(launch, log));
+    let mut child = match res {
+        Ok(child) => child,
+        Err(err) => {
+            launches.lock().unwrap().running.remove(&id);
+            return BatchStatus::Failed {
+                error: format!("{err:?}"),
+            };
+        },
+    };
+    let (res, timed_out) = wait_or_kill(&mut child, &command, id, timeout, &kill).await;
+    launches.lock().unwrap().running.remove(&id);
+
+    match res {
+        _ if timed_out => BatchStatus::TimedOut,
+        Ok(status) => BatchStatus::Exited {
+            exit_code: exit_code(status),
+        },
+        Err(err) => BatchStatus::Failed {
+            error: format!("Failed to wait for {command:?} process to exit: {err}"),
+        },
+    }
+}
+
+/// Spawns the command of a launch and answers the client.
+async fn start_launch(
+    launch: Launch,
+    mut stream: BufStream<TcpStream>,
+    launches: &Mutex<Launches>,
+) -> Result<LaunchedChild> {
+    let (id, kill) = launches
+        .lock()
+        .unwrap()
+        .add(&launch.command, &launch.command_args);
+    let command = launch.command.clone();
+    let timeout = launch.timeout_ms.map(Duration::from_millis);
+    let detach = launch.detach;
+    let kill_on_disconnect = launch.kill_on_disconnect;
+    let idle_timeout = launch.idle_timeout_ms.map(Duration::from_millis);
+    let merge_stderr = launch.merge_stderr;
+    let res = detached_log(detach, id).and_then(|log| spawn_child(launch, log));
+    if let Err(err) = &res {
+        launches.lock().unwrap().running.remove(&id);
+        stream.write_all(error_response(err).as_bytes()).await.ok();
+    } else {
+        stream.write_all(b"OK\n").await.ok();
+        if detach {
+            write_frame(&mut stream, FrameKind::LaunchId, &id.to_be_bytes())
+                .await
+                .ok();
+        }
+    }
+    stream.flush().await.ok();
+
+    let child = res?;
+    Ok(LaunchedChild {
+        id,
+        command,
+        child,
+        timeout,
+        kill,
+        kill_on_disconnect,
+        idle_timeout,
+        merge_stderr,
+        // Closing the connection lets the client of a detached launch go.
+        stream: (!detach).then_some(stream),
+    })
+}
+
+/// Returns the line to answer a launch with when its command failed to start.
+fn error_response(err: &anyhow::Error) -> String {
+    let response = ErrorResponse {
+        message: format!("{err:#}"),
+        errno: err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>()?.raw_os_error()),
+    };
+    let json = serde_json::to_string(&response).expect("error response should serialize");
+    format!("{ERROR_RESPONSE_PREFIX}{json}\n")
+}
+
+/// Creates the file logging the output of a detached launch, see
+/// [`detached_log_path`]. An existing file, or a symlink, at its path is an
+/// error rather than something to write through.
+fn detached_log(detach: bool, id: u64) -> Result<Option<File>> {
+    if !detach {
+        return Ok(None);
     }
+    let path = detached_log_path(id)?;
+    let log = File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to create log file {path:?}"))?;
+    debug!(id, path:?; "logging output of detached launch");
+    Ok(Some(log))
+}
+
+/// Returns the path of the log of detached launch `id`, in the runtime dir of
+/// the user and named after the server process too, since launch ids start
+/// over when the server restarts.
+fn detached_log_path(id: u64) -> Result<PathBuf> {
+    let pid = std::process::id();
+    Ok(runtime_dir()?.join(format!("krun-launch-{pid}-{id}.log")))
 }
 
-async fn handle_connection(mut stream: BufStream<TcpStream>) -> Result<(PathBuf, Child)> {
-    let mut envs: HashMap<String, String> = env::vars().collect();
+/// Returns the environment of a launched command: `env` on top of the whole
+/// environment of the server, or only of its [`CLEAN_ENV_VARS`] for a launch
+/// with a clean environment.
+fn proc_env(clean_env: bool, env: HashMap<String, String>) -> HashMap<String, String> {
+    let mut envs: HashMap<String, String> = if clean_env {
+        CLEAN_ENV_VARS
+            .into_iter()
+            .filter_map(|key| Some((key.to_owned(), env::var(key).ok()?)))
+            .collect()
+    } else {
+        env::vars().collect()
+    };
+    envs.extend(env);
+    envs
+}
+
+/// Files the output of a launch is redirected to, see
+/// [`Launch::stdout_path`]. They are opened by the child process once it runs
+/// as the user of the launch and with its umask, so that they end up with the
+/// same owner and mode as if the command had created them.
+#[derive(Debug)]
+struct OutputRedirect {
+    stdout_path: Option<CString>,
+    stderr_path: Option<CString>,
+    append: bool,
+    /// Whether stderr goes to `stdout_path` too.
+    merge_stderr: bool,
+}
+
+impl OutputRedirect {
+    fn new(
+        stdout_path: Option<PathBuf>,
+        stderr_path: Option<PathBuf>,
+        append: bool,
+        merge_stderr: bool,
+    ) -> Result<Self> {
+        if merge_stderr && stderr_path.is_some() {
+            return Err(anyhow!(
+                "stderr can't be both merged into stdout and redirected"
+            ));
+        }
+        let c_path = |path: PathBuf| {
+            CString::new(path.into_os_string().into_vec())
+                .context("Output file path contains a NUL byte")
+        };
+        Ok(Self {
+            merge_stderr: merge_stderr && stdout_path.is_some(),
+            stdout_path: stdout_path.map(c_path).transpose()?,
+            stderr_path: stderr_path.map(c_path).transpose()?,
+            append,
+        })
+    }
+
+    fn redirects_stdout(&self) -> bool {
+        self.stdout_path.is_some()
+    }
+
+    fn redirects_stderr(&self) -> bool {
+        self.stderr_path.is_some() || self.merge_stderr
+    }
 
+    /// Opens the files as stdout and stderr. A relative path is relative to
+    /// the working directory of the child process. Only makes system calls,
+    /// so that it can be used between fork and exec.
+    fn apply(&self) -> rustix::io::Result<()> {
+        let flags = OFlags::WRONLY
+            | OFlags::CREATE
+            | OFlags::CLOEXEC
+            | if self.append {
+                OFlags::APPEND
+            } else {
+                OFlags::TRUNC
+            };
+        let mode = Mode::from_bits_truncate(0o666);
+        if let Some(path) = &self.stdout_path {
+            dup2_stdout(open(path.as_c_str(), flags, mode)?)?;
+        }
+        if let Some(path) = &self.stderr_path {
+            dup2_stderr(open(path.as_c_str(), flags, mode)?)?;
+        } else if self.merge_stderr {
+            dup2_stderr(rustix::stdio::stdout())?;
+        }
+        Ok(())
+    }
+}
+
+/// Spawns the command of a launch, with its output logged to `log` for a
+/// detached launch unless redirected elsewhere.
+fn spawn_child(launch: Launch, log: Option<File>) -> Result<Child> {
     let Launch {
         command,
         command_args,
         env,
-    } = read_request(&mut stream).await?;
-    debug!(command:?, command_args:?, env:?; "received launch request");
-    envs.extend(env);
+        cwd,
+        forward_stdin,
+        user,
+        argv0,
+        clean_env,
+        stdin,
+        capabilities,
+        umask,
+        stdout_path,
+        stderr_path,
+        append_output,
+        merge_stderr,
+        ..
+    } = launch;
+    let output_redirect =
+        OutputRedirect::new(stdout_path, stderr_path, append_output, merge_stderr)?;
+    if stdin.len() > MAX_STDIN_LEN {
+        return Err(anyhow!(
+            "stdin of {} bytes is over the limit of {MAX_STDIN_LEN} bytes",
+            stdin.len()
+        ));
+    }
+    let mut envs = proc_env(clean_env, env);
+
+    let drop_privileges = match user {
+        Some(user) => {
+            let user = TargetUser::resolve(&user)?;
+            let steps = user.privilege_drop()?;
+            envs.insert("HOME".to_owned(), user.home.to_string_lossy().into_owned());
+            envs.insert("USER".to_owned(), user.name.clone());
+            envs.insert("LOGNAME".to_owned(), user.name);
+            steps
+        },
+        None => vec![],
+    };
+    let drop_capabilities = capabilities.as_ref().map(CapabilityDrop::new).transpose()?;
 
-    let (stdout, stderr) = make_stdout_stderr(&command, &envs)?;
+    // The client's working directory may not exist in the guest, in which
+    // case fall back to the user's home directory.
+    let cwd = if cwd.is_dir() {
+        Some(cwd)
+    } else {
+        envs.get("HOME").map(PathBuf::from)
+    };
 
-    let res = Command::new(&command)
+    let mut cmd = std::process::Command::new(&command);
+    if let Some(argv0) = argv0 {
+        cmd.arg0(argv0);
+    }
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    // There is no client to forward the stdin of detached launches.
+    let detach = log.is_some();
+    let forward_stdin = forward_stdin && !detach && stdin.is_empty();
+    // Redirected output is set up by the child process, see below.
+    let (stdout, stderr) = match log {
+        Some(log) => {
+            let stderr = log.try_clone().context("Failed to duplicate log file")?;
+            (Stdio::from(log), Stdio::from(stderr))
+        },
+        None => (Stdio::piped(), Stdio::piped()),
+    };
+    cmd.stdout(if output_redirect.redirects_stdout() {
+        Stdio::null()
+    } else {
+        stdout
+    });
+    cmd.stderr(if output_redirect.redirects_stderr() {
+        Stdio::null()
+    } else {
+        stderr
+    });
+    if detach {
+        // Detaches the command from the session of the server, which also
+        // makes it the leader of its own process group.
+        //
+        // SAFETY: setsid() is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(|| setsid().map(drop).map_err(io::Error::from));
+        }
+    } else {
+        // So that signals from the client reach everything the command spawns.
+        cmd.process_group(0);
+    }
+    if let Some(mask) = umask {
+        let mask = Mode::from_bits(mask).with_context(|| format!("Invalid umask {mask:#o}"))?;
+        // SAFETY: umask() is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                rustix::process::umask(mask);
+                Ok(())
+            });
+        }
+    }
+    if !drop_privileges.is_empty() || drop_capabilities.is_some() {
+        // SAFETY: The closure only makes system calls, without allocating or
+        // looking anything up, as the user and the capabilities were resolved
+        // before forking.
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(caps) = &drop_capabilities {
+                    caps.apply_to_bounding_set()?;
+                }
+                for step in &drop_privileges {
+                    step.apply()?;
+                }
+                if let Some(caps) = &drop_capabilities {
+                    caps.apply()?;
+                }
+                Ok(())
+            });
+        }
+    }
+    if output_redirect.redirects_stdout() || output_redirect.redirects_stderr() {
+        // SAFETY: The closure only makes system calls, the paths were
+        // converted before forking. It runs after the privileges were dropped
+        // and the umask set, as closures run in the order they're added.
+        unsafe {
+            cmd.pre_exec(move || output_redirect.apply().map_err(io::Error::from));
+        }
+    }
+    let mut child = Command::from(cmd)
         .args(command_args)
         .envs(envs)
-        .stdin(Stdio::null())
-        .stdout(stdout)
-        .stderr(stderr)
+        .stdin(if forward_stdin || !stdin.is_empty() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
         .spawn()
-        .with_context(|| format!("Failed to execute {command:?} as child process"));
-    if let Err(err) = &res {
-        let msg = format!("{err:?}");
-        stream.write_all(msg.as_bytes()).await.ok();
-    } else {
-        stream.write_all(b"OK").await.ok();
+        .with_context(|| format!("Failed to execute {command:?} as child process"))?;
+    if let Some(mut pipe) = child.stdin.take().filter(|_| !stdin.is_empty()) {
+        // Dropping the pipe once written closes the stdin of the child process.
+        tokio::spawn(async move {
+            if let Err(err) = pipe.write_all(&stdin).await {
+                debug!(err:%; "failed to write stdin");
+            }
+        });
     }
-    stream.flush().await.ok();
+    Ok(child)
+}
+
+async fn wait_for_child(
+    launched: LaunchedChild,
+    launches: Arc<Mutex<Launches>>,
+) -> (PathBuf, ChildResult) {
+    let LaunchedChild {
+        id,
+        command,
+        mut child,
+        timeout,
+        kill,
+        kill_on_disconnect,
+        idle_timeout,
+        merge_stderr,
+        stream,
+    } = launched;
+    let pgid = child.id();
+
+    let Some(stream) = stream else {
+        let (res, _) = wait_or_kill(&mut child, &command, id, timeout, &kill).await;
+        launches.lock().unwrap().running.remove(&id);
+        return (command, res);
+    };
+    let (reader, mut writer) = split(stream);
+
+    let client_task = tokio::spawn(read_client_frames(
+        reader,
+        child.stdin.take(),
+        pgid,
+        (kill_on_disconnect || idle_timeout.is_some()).then(|| kill.clone()),
+        idle_timeout,
+    ));
+
+    let (output_tx, mut output_rx) = mpsc::channel(16);
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(read_output(FrameKind::Stdout, stdout, output_tx.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        let kind = if merge_stderr {
+            FrameKind::Stdout
+        } else {
+            FrameKind::Stderr
+        };
+        tokio::spawn(read_output(kind, stderr, output_tx.clone()));
+    }
+    drop(output_tx);
+
+    let send_output = async {
+        let mut client_gone = false;
+        // Keep draining the output even if the client went away, so the child
+        // process doesn't block on a full pipe.
+        while let Some((kind, data)) = output_rx.recv().await {
+            if !client_gone && write_frame(&mut writer, kind, &data).await.is_err() {
+                debug!("client went away, discarding output");
+                client_gone = true;
+            }
+        }
+    };
+    let wait = wait_or_kill(&mut child, &command, id, timeout, &kill);
+    let ((res, timed_out), ()) = tokio::join!(wait, send_output);
+    launches.lock().unwrap().running.remove(&id);
 
-    res.map(|child| (command, child))
+    client_task.abort();
+    if timed_out {
+        write_frame(&mut writer, FrameKind::TimedOut, &[])
+            .await
+            .ok();
+    }
+    if let Ok(status) = res {
+        report_exit_status(&mut writer, status).await;
+    }
+
+    (command, res)
+}
+
+/// Waits for the child process to exit, terminating its process group if it
+/// runs past `timeout` or `kill` is notified. Also returns whether it timed
+/// out.
+async fn wait_or_kill(
+    child: &mut Child,
+    command: &Path,
+    id: u64,
+    timeout: Option<Duration>,
+    kill: &Notify,
+) -> (ChildResult, bool) {
+    let pgid = child.id();
+    let deadline = async {
+        match timeout {
+            Some(timeout) => time::sleep(timeout).await,
+            None => future::pending().await,
+        }
+    };
+    let timed_out = tokio::select! {
+        res = child.wait() => return (res, false),
+        () = deadline => {
+            debug!(command:?, timeout:?; "child process timed out");
+            true
+        },
+        () = kill.notified() => {
+            debug!(command:?, id; "child process killed by client request");
+            false
+        },
+    };
+    signal_process_group(pgid, Signal::SIGTERM);
+    if let Ok(res) = time::timeout(KILL_GRACE_PERIOD, child.wait()).await {
+        return (res, timed_out);
+    }
+    signal_process_group(pgid, Signal::SIGKILL);
+    (child.wait().await, timed_out)
+}
+
+/// Handles the frames the client sends after the launch request: the stdin of
+/// the child process and signals for its process group. Once the client is
+/// gone, or has sent nothing for `idle_timeout` if it sends heartbeats, `kill`
+/// is notified to reap the child process.
+async fn read_client_frames<R>(
+    mut reader: R,
+    mut stdin: Option<ChildStdin>,
+    pgid: Option<u32>,
+    kill: Option<Arc<Notify>>,
+    idle_timeout: Option<Duration>,
+) where
+    R: AsyncRead + Unpin,
+{
+    let client_gone = || {
+        if let Some(kill) = &kill {
+            debug!("client is gone, killing child process");
+            kill.notify_one();
+        }
+    };
+    let mut buf = Vec::new();
+    loop {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        let read = reader.read_exact(&mut header);
+        let res = match idle_timeout {
+            Some(timeout) => time::timeout(timeout, read)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => read.await,
+        };
+        if let Err(err) = res {
+            debug!(err:%; "client stopped sending frames");
+            client_gone();
+            return;
+        }
+        let (kind, len) = parse_frame_header(&header);
+        let valid_len = match kind {
+            Ok(FrameKind::Stdin) => len <= MAX_STDIN_FRAME_LEN,
+            Ok(FrameKind::Signal) => len == 4,
+            kind => {
+                debug!(kind:?; "unexpected frame from client");
+                return;
+            },
+        };
+        if !valid_len {
+            debug!(kind:?, len; "invalid frame length from client");
+            return;
+        }
+        buf.resize(len as usize, 0);
+        if let Err(err) = reader.read_exact(&mut buf).await {
+            debug!(err:%; "client stopped sending frames");
+            client_gone();
+            return;
+        }
+
+        match kind {
+            Ok(FrameKind::Stdin) if buf.is_empty() => {
+                // Dropping the pipe closes the stdin of the child process.
+                stdin = None;
+            },
+            Ok(FrameKind::Stdin) => {
+                if let Some(pipe) = &mut stdin {
+                    if let Err(err) = pipe.write_all(&buf).await {
+                        debug!(err:%; "stopped forwarding stdin");
+                        stdin = None;
+                    }
+                }
+            },
+            Ok(FrameKind::Signal) if buf == 0i32.to_be_bytes() => {
+                trace!("heartbeat from client");
+            },
+            _ => {
+                let signal = <[u8; 4]>::try_from(buf.as_slice())
+                    .ok()
+                    .map(i32::from_be_bytes)
+                    .and_then(|signo| Signal::try_from(signo).ok());
+                match signal {
+                    Some(signal) => signal_process_group(pgid, signal),
+                    None => debug!(payload:? = buf; "invalid signal from client"),
+                }
+            },
+        }
+    }
+}
+
+fn signal_process_group(pgid: Option<u32>, signal: Signal) {
+    let Some(pgid) = pgid else {
+        return;
+    };
+    debug!(pgid, signal:?; "signaling child process group");
+    if let Err(err) = killpg(Pid::from_raw(pgid as i32), signal) {
+        debug!(err:%; "failed to signal child process group");
+    }
+}
+
+async fn read_output<R>(kind: FrameKind, mut pipe: R, output_tx: mpsc::Sender<(FrameKind, Vec<u8>)>)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![0u8; 8192];
+    loop {
+        match pipe.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(len) => {
+                if output_tx.send((kind, buf[..len].to_vec())).await.is_err() {
+                    break;
+                }
+            },
+            Err(err) => {
+                debug!(err:%, kind:?; "failed to read output of child process");
+                break;
+            },
+        }
+    }
+}
+
+async fn write_frame<W>(stream: &mut W, kind: FrameKind, data: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = u32::try_from(data.len()).expect("frame payload should fit in u32");
+    stream.write_all(&frame_header(kind, len)).await?;
+    stream.write_all(data).await?;
+    stream.flush().await
+}
+
+/// Sends the exit code of the child process back to the client, following
+/// the shell convention of `128 + signal` for processes killed by a signal.
+async fn report_exit_status<W>(stream: &mut W, status: ExitStatus)
+where
+    W: AsyncWrite + Unpin,
+{
+    write_frame(
+        stream,
+        FrameKind::ExitCode,
+        &exit_code(status).to_be_bytes(),
+    )
+    .await
+    .ok();
+}
+
+/// Returns the exit code of a process, or `128 + signal` if it was killed by a
+/// signal.
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or_else(|| {
+        128 + status
+            .signal()
+            .expect("either one of status code or signal should be set")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write as _;
+    use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+
+    use nix::unistd::geteuid;
+
+    use super::*;
+
+    async fn read_request_from(data: Vec<u8>) -> Result<Option<Request>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            std::net::TcpStream::connect(addr)
+                .unwrap()
+                .write_all(&data)
+                .unwrap();
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        client.join().unwrap();
+        read_request(&mut BufStream::new(stream)).await
+    }
+
+    /// Sends a launch request from a blocking client, which returns all it
+    /// got back and when the launch was accepted or refused.
+    fn spawn_client(
+        addr: std::net::SocketAddr,
+        launch: Launch,
+    ) -> tokio::task::JoinHandle<(Vec<u8>, Instant)> {
+        tokio::task::spawn_blocking(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            let json = serde_json::to_vec(&Request::Launch(launch)).unwrap();
+            stream
+                .write_all(&frame_header(FrameKind::Request, json.len() as u32))
+                .unwrap();
+            stream.write_all(&json).unwrap();
+            let mut reader = std::io::BufReader::new(stream);
+            let mut resp = Vec::new();
+            while std::io::BufRead::read_until(&mut reader, b'\n', &mut resp).unwrap() > 0 {
+                if resp.ends_with(b"OK\n") {
+                    break;
+                }
+            }
+            let answered = Instant::now();
+            std::io::Read::read_to_end(&mut reader, &mut resp).unwrap();
+            (resp, answered)
+        })
+    }
+
+    fn launch_with_env(key: &str, value: &str) -> Launch {
+        Launch {
+            command: PathBuf::from("env"),
+            command_args: vec![],
+            env: HashMap::from([(key.to_owned(), value.to_owned())]),
+            cwd: PathBuf::from("/"),
+            forward_stdin: false,
+            timeout_ms: None,
+            user: None,
+            detach: false,
+            argv0: None,
+            fail_if_busy: false,
+            clean_env: false,
+            stdin: vec![],
+            kill_on_disconnect: false,
+            idle_timeout_ms: None,
+            capabilities: None,
+            umask: None,
+            stdout_path: None,
+            stderr_path: None,
+            append_output: false,
+            merge_stderr: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn read_framed_request() {
+        let launch = launch_with_env("PAYLOAD", "\nEOM\n");
+        let json = serde_json::to_vec(&Request::Launch(launch.clone())).unwrap();
+        let mut data = frame_header(FrameKind::Request, json.len() as u32).to_vec();
+        data.extend_from_slice(&json);
+
+        let request = read_request_from(data).await.unwrap();
+        assert_eq!(request, Some(Request::Launch(launch)));
+    }
+
+    #[tokio::test]
+    async fn read_legacy_request() {
+        let launch = launch_with_env("GEOMETRY", "EOM");
+        let mut data = serde_json::to_vec(&launch).unwrap();
+        data.extend_from_slice(b"\nEOM\n");
+
+        let request = read_request_from(data).await.unwrap();
+        assert_eq!(request, Some(Request::Launch(launch)));
+
+        // As sent by the first krun release, with none of the later fields.
+        let data = br#"{"command":"/usr/bin/ls","command_args":["-l"],"env":{"FOO":"bar"}}
+EOM
+"#;
+        let request = read_request_from(data.to_vec()).await.unwrap();
+        let Some(Request::Launch(launch)) = request else {
+            panic!("expected a launch request, got {request:?}");
+        };
+        assert_eq!(launch.command, PathBuf::from("/usr/bin/ls"));
+        assert_eq!(launch.command_args, ["-l"]);
+        assert_eq!(
+            launch.env,
+            HashMap::from([("FOO".to_owned(), "bar".to_owned())])
+        );
+        assert_eq!(launch.cwd, PathBuf::new());
+        assert!(!launch.forward_stdin && !launch.detach);
+        assert_eq!((launch.timeout_ms, launch.user), (None, None));
+    }
+
+    #[tokio::test]
+    async fn kill_child_after_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::task::spawn_blocking(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            let launch = Launch {
+                command: PathBuf::from("sleep"),
+                command_args: vec!["10".to_owned()],
+                timeout_ms: Some(100),
+                ..launch_with_env("KRUN_TEST", "1")
+            };
+            let json = serde_json::to_vec(&Request::Launch(launch)).unwrap();
+            stream
+                .write_all(&frame_header(FrameKind::Request, json.len() as u32))
+                .unwrap();
+            stream.write_all(&json).unwrap();
+            let mut resp = Vec::new();
+            std::io::Read::read_to_end(&mut stream, &mut resp).unwrap();
+            resp
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let launches = Arc::default();
+        let (Accepted::Launch(launch), stream) =
+            handle_connection(BufStream::new(stream), Instant::now(), &launches)
+                .await
+                .unwrap()
+                .unwrap()
+        else {
+            panic!("expected a launch request");
+        };
+        let launched = start_launch(launch, stream, &launches).await.unwrap();
+        assert_eq!(launched.timeout, Some(Duration::from_millis(100)));
+
+        let (_, res) = wait_for_child(launched, launches.clone()).await;
+        assert!(launches.lock().unwrap().list().is_empty());
+        assert_eq!(res.unwrap().signal(), Some(Signal::SIGTERM as i32));
+        let mut expected = b"OK\n".to_vec();
+        expected.extend(frame_header(FrameKind::TimedOut, 0));
+        expected.extend(frame_header(FrameKind::ExitCode, 4));
+        expected.extend((128 + Signal::SIGTERM as i32).to_be_bytes());
+        assert_eq!(client.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn kill_running_launch() {
+        let launches = Arc::<Mutex<Launches>>::default();
+        let mut cmd = std::process::Command::new("sleep");
+        cmd.arg("10").process_group(0);
+        let child = Command::from(cmd).spawn().unwrap();
+        let (id, kill) = launches
+            .lock()
+            .unwrap()
+            .add(Path::new("sleep"), &["10".to_owned()]);
+        let list = launches.lock().unwrap().list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(
+            (list[0].id, &*list[0].command_args),
+            (id, &["10".to_owned()][..])
+        );
+        assert!(launches.lock().unwrap().kill(id + 1).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::task::spawn_blocking(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            let mut resp = Vec::new();
+            std::io::Read::read_to_end(&mut stream, &mut resp).unwrap();
+            resp
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        launches.lock().unwrap().kill(id).unwrap();
+        let launched = LaunchedChild {
+            id,
+            command: PathBuf::from("sleep"),
+            child,
+            timeout: None,
+            kill,
+            kill_on_disconnect: false,
+            idle_timeout: None,
+            merge_stderr: false,
+            stream: Some(BufStream::new(stream)),
+        };
+
+        let (_, res) = wait_for_child(launched, launches.clone()).await;
+        assert_eq!(res.unwrap().signal(), Some(Signal::SIGTERM as i32));
+        assert!(launches.lock().unwrap().list().is_empty());
+        let mut expected = frame_header(FrameKind::ExitCode, 4).to_vec();
+        expected.extend((128 + Signal::SIGTERM as i32).to_be_bytes());
+        assert_eq!(client.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn reap_child_when_heartbeats_stop() {
+        // A client that is only suspended, sending nothing but keeping the
+        // connection open, is left alone without an idle timeout.
+        let kill = Arc::new(Notify::new());
+        let (client, reader) = tokio::io::duplex(64);
+        let task = tokio::spawn(read_client_frames(
+            reader,
+            None,
+            None,
+            Some(kill.clone()),
+            None,
+        ));
+        assert!(time::timeout(Duration::from_millis(300), kill.notified())
+            .await
+            .is_err());
+        assert!(!task.is_finished());
+        drop(client);
+        task.await.unwrap();
+
+        let kill = Arc::new(Notify::new());
+        let idle_timeout = Some(Duration::from_millis(200));
+        let (mut client, reader) = tokio::io::duplex(64);
+        let task = tokio::spawn(read_client_frames(
+            reader,
+            None,
+            None,
+            Some(kill.clone()),
+            idle_timeout,
+        ));
+
+        // Heartbeats keep the child process alive.
+        for _ in 0..3 {
+            client
+                .write_all(&frame_header(FrameKind::Signal, 4))
+                .await
+                .unwrap();
+            client.write_all(&0i32.to_be_bytes()).await.unwrap();
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(!task.is_finished());
+
+        // The client stops sending them, but leaves the connection open.
+        time::timeout(Duration::from_secs(1), kill.notified())
+            .await
+            .unwrap();
+        task.await.unwrap();
+        drop(client);
+
+        // A client that goes away is reaped without waiting for the timeout,
+        // or without any.
+        let (client, reader) = tokio::io::duplex(64);
+        drop(client);
+        read_client_frames(reader, None, None, Some(kill.clone()), None).await;
+        time::timeout(Duration::from_secs(1), kill.notified())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_client_frames() {
+        for header in [
+            frame_header(FrameKind::Stdin, u32::MAX),
+            frame_header(FrameKind::Signal, 8),
+        ] {
+            // The client keeps the connection open, but the payload isn't
+            // waited for.
+            let (mut client, reader) = tokio::io::duplex(64);
+            client.write_all(&header).await.unwrap();
+            time::timeout(
+                Duration::from_secs(1),
+                read_client_frames(reader, None, None, None, None),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn detach_child_from_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::task::spawn_blocking(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            let launch = Launch {
+                command: PathBuf::from("sh"),
+                command_args: vec!["-c".to_owned(), "echo detached; sleep 10".to_owned()],
+                forward_stdin: true,
+                detach: true,
+                ..launch_with_env("KRUN_TEST", "1")
+            };
+            let json = serde_json::to_vec(&Request::Launch(launch)).unwrap();
+            stream
+                .write_all(&frame_header(FrameKind::Request, json.len() as u32))
+                .unwrap();
+            stream.write_all(&json).unwrap();
+            let mut resp = Vec::new();
+            std::io::Read::read_to_end(&mut stream, &mut resp).unwrap();
+            resp
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let launches = Arc::default();
+        let (Accepted::Launch(launch), stream) =
+            handle_connection(BufStream::new(stream), Instant::now(), &launches)
+                .await
+                .unwrap()
+                .unwrap()
+        else {
+            panic!("expected a launch request");
+        };
+        let launched = start_launch(launch, stream, &launches).await.unwrap();
+        assert!(launched.stream.is_none());
+        assert!(launched.child.stdout.is_none() && launched.child.stdin.is_none());
+        let id = launched.id;
+        let child = tokio::spawn(wait_for_child(launched, launches.clone()));
+
+        // The client got the launch id and the connection closed while the
+        // command is still running.
+        let mut expected = b"OK\n".to_vec();
+        expected.extend(frame_header(FrameKind::LaunchId, 8));
+        expected.extend(id.to_be_bytes());
+        assert_eq!(client.await.unwrap(), expected);
+        assert_eq!(launches.lock().unwrap().list().len(), 1);
+
+        let log = detached_log_path(id).unwrap();
+        for _ in 0..100 {
+            if !std::fs::read(&log).unwrap().is_empty() {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        launches.lock().unwrap().kill(id).unwrap();
+        let (_, res) = child.await.unwrap();
+        assert_eq!(res.unwrap().signal(), Some(Signal::SIGTERM as i32));
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "detached\n");
+        assert_eq!(std::fs::metadata(&log).unwrap().mode() & 0o777, 0o600);
+        std::fs::remove_file(log).unwrap();
+    }
+
+    #[test]
+    fn refuse_existing_detached_log() {
+        let id = u64::MAX;
+        let log = detached_log_path(id).unwrap();
+        std::os::unix::fs::symlink("/dev/null", &log).unwrap();
+        let res = detached_log(true, id);
+        std::fs::remove_file(&log).unwrap();
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn queue_launches_past_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let launches = Arc::<Mutex<Launches>>::default();
+        let slots = Arc::new(Semaphore::new(1));
+        let mut sleep = launch_with_env("KRUN_TEST", "1");
+        sleep.command = PathBuf::from("sleep");
+        sleep.command_args = vec!["0.5".to_owned()];
+
+        let started = Instant::now();
+        let mut clients = vec![];
+        let mut tasks = vec![];
+        for fail_if_busy in [false, true, false] {
+            let launch = Launch {
+                fail_if_busy,
+                ..sleep.clone()
+            };
+            clients.push(spawn_client(addr, launch));
+            let (stream, _) = listener.accept().await.unwrap();
+            let (Accepted::Launch(launch), stream) =
+                handle_connection(BufStream::new(stream), Instant::now(), &launches)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            else {
+                panic!("expected a launch request");
+            };
+            tasks.push(tokio::spawn(run_launch(
+                launch,
+                stream,
+                launches.clone(),
+                Some(slots.clone()),
+            )));
+            // Make sure the first launch takes the only slot.
+            while launches.lock().unwrap().list().is_empty() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        let mut exited = b"OK\n".to_vec();
+        exited.extend(frame_header(FrameKind::ExitCode, 4));
+        exited.extend(0i32.to_be_bytes());
+        let mut results = vec![];
+        for (client, task) in clients.into_iter().zip(tasks) {
+            results.push((client.await.unwrap(), task.await.unwrap()));
+        }
+        let [(first, first_res), (refused, refused_res), (queued, queued_res)] =
+            <[_; 3]>::try_from(results).unwrap();
+
+        assert_eq!(first.0, exited);
+        assert!(first_res.unwrap().1.unwrap().success());
+        let refused = String::from_utf8(refused.0).unwrap();
+        let json = refused
+            .strip_prefix(ERROR_RESPONSE_PREFIX)
+            .and_then(|json| json.strip_suffix('\n'))
+            .unwrap();
+        let response: ErrorResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            response.message,
+            "Too many launches are running already, try again later"
+        );
+        assert!(refused_res.is_err());
+        // The last launch was only admitted once the first one was done.
+        assert_eq!(queued.0, [b"QUEUED\n".as_slice(), &exited].concat());
+        assert!(queued.1 - started >= Duration::from_millis(500));
+        assert!(queued_res.unwrap().1.unwrap().success());
+        assert!(launches.lock().unwrap().list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn drop_queued_launch_when_client_leaves() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let launches = Arc::<Mutex<Launches>>::default();
+        let slots = Arc::new(Semaphore::new(0));
+
+        let client = tokio::task::spawn_blocking(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            let json =
+                serde_json::to_vec(&Request::Launch(launch_with_env("KRUN_TEST", "1"))).unwrap();
+            stream
+                .write_all(&frame_header(FrameKind::Request, json.len() as u32))
+                .unwrap();
+            stream.write_all(&json).unwrap();
+            let mut resp = [0u8; 7];
+            std::io::Read::read_exact(&mut stream, &mut resp).unwrap();
+            assert_eq!(&resp, b"QUEUED\n");
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let (Accepted::Launch(launch), stream) =
+            handle_connection(BufStream::new(stream), Instant::now(), &launches)
+                .await
+                .unwrap()
+                .unwrap()
+        else {
+            panic!("expected a launch request");
+        };
+        let task = tokio::spawn(run_launch(
+            launch,
+            stream,
+            launches.clone(),
+            Some(slots.clone()),
+        ));
+        client.await.unwrap();
+
+        // The launch gives up its place in the queue without a slot coming free.
+        let res = time::timeout(Duration::from_secs(1), task).await.unwrap();
+        assert!(res.unwrap().is_err());
+        assert!(launches.lock().unwrap().list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_batch_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let launches = Arc::<Mutex<Launches>>::default();
+        let batch_launch = |command: &str, args: &[&str]| Launch {
+            command: PathBuf::from(command),
+            command_args: args.iter().map(|&arg| arg.to_owned()).collect(),
+            ..launch_with_env("KRUN_TEST", "1")
+        };
+
+        for (policy, expected) in [
+            (
+                BatchPolicy::StopOnFailure,
+                vec![BatchStatus::Exited { exit_code: 3 }, BatchStatus::Skipped],
+            ),
+            (
+                BatchPolicy::Continue,
+                vec![
+                    BatchStatus::Exited { exit_code: 3 },
+                    BatchStatus::Exited { exit_code: 0 },
+                ],
+            ),
+        ] {
+            let request = Request::LaunchBatch {
+                launches: vec![
+                    batch_launch("sh", &["-c", "exit 3"]),
+                    batch_launch("true", &[]),
+                ],
+                policy,
+            };
+            let client = tokio::task::spawn_blocking(move || {
+                let mut stream = std::net::TcpStream::connect(addr).unwrap();
+                let json = serde_json::to_vec(&request).unwrap();
+                stream
+                    .write_all(&frame_header(FrameKind::Request, json.len() as u32))
+                    .unwrap();
+                stream.write_all(&json).unwrap();
+                let mut resp = String::new();
+                std::io::Read::read_to_string(&mut stream, &mut resp).unwrap();
+                resp
+            });
+            let (stream, _) = listener.accept().await.unwrap();
+            let (
+                Accepted::Batch {
+                    launches: batch,
+                    policy,
+                },
+                stream,
+            ) = handle_connection(BufStream::new(stream), Instant::now(), &launches)
+                .await
+                .unwrap()
+                .unwrap()
+            else {
+                panic!("expected a batch request");
+            };
+            run_batch(batch, policy, stream, launches.clone(), None)
+                .await
+                .unwrap();
+
+            let statuses: Vec<BatchStatus> = serde_json::from_str(&client.await.unwrap()).unwrap();
+            assert_eq!(statuses, expected);
+            assert!(launches.lock().unwrap().list().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn spawn_child_with_argv0() {
+        let mut launch = launch_with_env("KRUN_TEST", "1");
+        launch.command = PathBuf::from("cat");
+        launch.command_args = vec!["/proc/self/cmdline".to_owned()];
+        launch.argv0 = Some("-kitty".to_owned());
+
+        let output = spawn_child(launch, None)
+            .unwrap()
+            .wait_with_output()
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"-kitty\0/proc/self/cmdline\0");
+    }
+
+    #[tokio::test]
+    async fn spawn_child_with_umask() {
+        let mut launch = launch_with_env("KRUN_TEST", "1");
+        launch.command = PathBuf::from("sh");
+        launch.command_args = vec!["-c".to_owned(), "umask".to_owned()];
+        launch.umask = Some(0o027);
+
+        let output = spawn_child(launch, None)
+            .unwrap()
+            .wait_with_output()
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"0027\n");
+    }
+
+    #[tokio::test]
+    async fn append_or_truncate_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut launch = launch_with_env("KRUN_TEST", "1");
+        launch.command = PathBuf::from("sh");
+        launch.command_args = vec!["-c".to_owned(), "echo out; echo err >&2".to_owned()];
+        launch.cwd = dir.path().to_owned();
+        launch.stdout_path = Some(PathBuf::from("out.log"));
+        launch.stderr_path = Some(dir.path().join("err.log"));
+
+        for append_output in [false, true] {
+            launch.append_output = append_output;
+            let output = spawn_child(launch.clone(), None)
+                .unwrap()
+                .wait_with_output()
+                .await
+                .unwrap();
+            assert!(output.stdout.is_empty() && output.stderr.is_empty());
+        }
+        let read_log = |name| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read_log("out.log"), "out\nout\n");
+        assert_eq!(read_log("err.log"), "err\nerr\n");
+
+        launch.append_output = false;
+        launch.stdout_path = Some(PathBuf::from("/dev/null"));
+        spawn_child(launch, None).unwrap().wait().await.unwrap();
+        assert_eq!(read_log("err.log"), "err\n");
+    }
+
+    #[tokio::test]
+    async fn create_output_as_user() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        // Switching users takes root, otherwise run as ourselves.
+        let user = if geteuid().is_root() {
+            TargetUser::resolve("nobody").unwrap()
+        } else {
+            TargetUser::resolve(&geteuid().to_string()).unwrap()
+        };
+        let mut launch = launch_with_env("KRUN_TEST", "1");
+        launch.command = PathBuf::from("true");
+        launch.cwd = dir.path().to_owned();
+        launch.user = Some(user.name);
+        launch.umask = Some(0o027);
+        launch.stdout_path = Some(PathBuf::from("out.log"));
+
+        spawn_child(launch, None).unwrap().wait().await.unwrap();
+        let metadata = fs::metadata(dir.path().join("out.log")).unwrap();
+        assert_eq!(metadata.uid(), user.uid.as_raw());
+        assert_eq!(metadata.mode() & 0o777, 0o640);
+    }
+
+    #[tokio::test]
+    async fn merge_stderr_into_stdout() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("out.log");
+        let mut launch = launch_with_env("KRUN_TEST", "1");
+        launch.command = PathBuf::from("sh");
+        launch.command_args = vec!["-c".to_owned(), "echo out; echo err >&2".to_owned()];
+        launch.stdout_path = Some(log_path.clone());
+        launch.merge_stderr = true;
+
+        let output = spawn_child(launch.clone(), None)
+            .unwrap()
+            .wait_with_output()
+            .await
+            .unwrap();
+        assert!(output.stdout.is_empty() && output.stderr.is_empty());
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "out\nerr\n");
+
+        launch.stderr_path = Some(dir.path().join("err.log"));
+        let err = spawn_child(launch, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "stderr can't be both merged into stdout and redirected"
+        );
+    }
+
+    #[tokio::test]
+    async fn spawn_child_with_stdin() {
+        let mut launch = launch_with_env("KRUN_TEST", "1");
+        launch.command = PathBuf::from("cat");
+        launch.stdin = b"config\n".to_vec();
+
+        let output = spawn_child(launch.clone(), None)
+            .unwrap()
+            .wait_with_output()
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"config\n");
+
+        launch.stdin = vec![0; MAX_STDIN_LEN + 1];
+        assert!(spawn_child(launch, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn error_response_with_errno() {
+        let mut launch = launch_with_env("KRUN_TEST", "1");
+        launch.command = PathBuf::from("/krun-test-missing");
+
+        let err = spawn_child(launch, None).unwrap_err();
+        let resp = error_response(&err);
+        let json = resp.strip_prefix(ERROR_RESPONSE_PREFIX).unwrap();
+        let response: ErrorResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(response.errno, Some(nix::errno::Errno::ENOENT as i32));
+        assert!(response
+            .message
+            .starts_with(r#"Failed to execute "/krun-test-missing" as child process: "#));
+        assert!(resp.ends_with('\n'));
+    }
+
+    #[test]
+    fn log_launch_without_env_values() {
+        let launch = launch_with_env("GITHUB_TOKEN", "hunter2");
+        let summary = format!("{:?}", LaunchSummary(&launch));
+        assert!(
+            summary.contains(r#"env_keys: ["GITHUB_TOKEN"]"#),
+            "{summary}"
+        );
+        assert!(!summary.contains("hunter2"), "{summary}");
+    }
+
+    #[test]
+    fn inherit_server_env() {
+        let env = HashMap::from([("HOME".to_owned(), "/krun-test".to_owned())]);
+        let envs = proc_env(false, env);
+
+        let mut expected: HashMap<String, String> = env::vars().collect();
+        expected.insert("HOME".to_owned(), "/krun-test".to_owned());
+        assert_eq!(envs, expected);
+    }
+
+    #[test]
+    fn clean_server_env() {
+        let env = HashMap::from([
+            ("HOME".to_owned(), "/krun-test".to_owned()),
+            ("KRUN_TEST".to_owned(), "1".to_owned()),
+        ]);
+        let envs = proc_env(true, env);
+
+        let mut expected: HashMap<String, String> = ["PATH", "TERM"]
+            .into_iter()
+            .filter_map(|key| Some((key.to_owned(), env::var(key).ok()?)))
+            .collect();
+        expected.insert("HOME".to_owned(), "/krun-test".to_owned());
+        expected.insert("KRUN_TEST".to_owned(), "1".to_owned());
+        assert_eq!(envs, expected);
+    }
+
+    #[tokio::test]
+    async fn read_no_request() {
+        assert_eq!(read_request_from(vec![]).await.unwrap(), None);
+        assert!(
+            read_request_from(frame_header(FrameKind::Request, u32::MAX).to_vec())
+                .await
+                .is_err()
+        );
+    }
 }
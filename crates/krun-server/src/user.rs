@@ -0,0 +1,137 @@
+use std::ffi::CString;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::{geteuid, getgrouplist, setgid, setgroups, setuid, Gid, Uid, User};
+
+/// A user to run a child process as.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TargetUser {
+    pub name: String,
+    pub uid: Uid,
+    pub gid: Gid,
+    pub home: PathBuf,
+    /// Supplementary groups of the user, including its primary group.
+    pub groups: Vec<Gid>,
+}
+
+/// A step of switching a process to a [`TargetUser`].
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum PrivilegeDrop {
+    SetGroups(Vec<Gid>),
+    SetGid(Gid),
+    SetUid(Uid),
+}
+
+impl TargetUser {
+    /// Looks up a user by name, or by uid if `user` is numeric.
+    pub fn resolve(user: &str) -> Result<Self> {
+        let found = match user.parse::<u32>() {
+            Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+            Err(_) => User::from_name(user),
+        }
+        .with_context(|| format!("Failed to look up user {user:?}"))?;
+        let User {
+            name,
+            uid,
+            gid,
+            dir,
+            ..
+        } = found.with_context(|| format!("User {user:?} does not exist"))?;
+        let c_name = CString::new(name.as_str()).context("User name contains a NUL byte")?;
+        let groups = getgrouplist(&c_name, gid)
+            .with_context(|| format!("Failed to get the groups of user {name:?}"))?;
+
+        Ok(Self {
+            name,
+            uid,
+            gid,
+            home: dir,
+            groups,
+        })
+    }
+
+    /// Returns what's needed to switch from the current user to this one,
+    /// failing if we aren't allowed to. Groups have to be changed while we
+    /// still have the privileges to do so, i.e. before the uid.
+    pub fn privilege_drop(&self) -> Result<Vec<PrivilegeDrop>> {
+        let euid = geteuid();
+        if euid == self.uid {
+            return Ok(vec![]);
+        }
+        if !euid.is_root() {
+            return Err(anyhow!(
+                "krun-server is not running as root, so it can't run commands as user {:?}",
+                self.name
+            ));
+        }
+
+        Ok(vec![
+            PrivilegeDrop::SetGroups(self.groups.clone()),
+            PrivilegeDrop::SetGid(self.gid),
+            PrivilegeDrop::SetUid(self.uid),
+        ])
+    }
+}
+
+impl PrivilegeDrop {
+    /// Only makes system calls, so that it can be used between fork and exec.
+    pub fn apply(&self) -> nix::Result<()> {
+        match self {
+            Self::SetGroups(groups) => setgroups(groups),
+            Self::SetGid(gid) => setgid(*gid),
+            Self::SetUid(uid) => setuid(*uid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_user() {
+        let root = TargetUser::resolve("root").unwrap();
+        assert_eq!(root.uid, Uid::from_raw(0));
+        assert_eq!(root.gid, Gid::from_raw(0));
+        assert!(root.groups.contains(&root.gid));
+        assert_eq!(TargetUser::resolve("0").unwrap(), root);
+
+        let err = TargetUser::resolve("krun-no-such-user").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"User "krun-no-such-user" does not exist"#
+        );
+    }
+
+    #[test]
+    fn drop_gid_before_uid() {
+        let user = TargetUser {
+            name: "nobody".to_owned(),
+            uid: Uid::from_raw(65534),
+            gid: Gid::from_raw(65534),
+            home: PathBuf::from("/nonexistent"),
+            groups: vec![Gid::from_raw(65534), Gid::from_raw(100)],
+        };
+        match user.privilege_drop() {
+            Ok(steps) => assert_eq!(
+                steps,
+                [
+                    PrivilegeDrop::SetGroups(user.groups.clone()),
+                    PrivilegeDrop::SetGid(user.gid),
+                    PrivilegeDrop::SetUid(user.uid),
+                ]
+            ),
+            Err(err) => {
+                assert!(!geteuid().is_root());
+                assert!(err.to_string().contains("not running as root"));
+            },
+        }
+
+        let current = TargetUser {
+            uid: geteuid(),
+            ..user
+        };
+        assert_eq!(current.privilege_drop().unwrap(), []);
+    }
+}
@@ -1,13 +1,17 @@
 use std::ffi::{c_char, CString};
 use std::os::fd::{IntoRawFd, OwnedFd};
 use std::path::Path;
-use std::{cmp, env};
+use std::{cmp, env, process};
 
 use anyhow::{anyhow, Context, Result};
 use krun::cli_options::options;
 use krun::cpu::{get_fallback_cores, get_performance_cores};
-use krun::env::{find_krun_exec, prepare_env_vars};
-use krun::launch::{launch_or_lock, LaunchResult};
+use krun::env::{
+    find_krun_exec, forwarded_sockets_env, prepare_env_vars, resolve_forwarded_sockets,
+};
+use krun::launch::{
+    launch_error_json, launch_or_lock, write_port_fd, LaunchError, LaunchOptions, LaunchResult,
+};
 use krun::net::{connect_to_passt, start_passt};
 use krun::types::MiB;
 use krun_sys::{
@@ -34,23 +38,73 @@ fn main() -> Result<()> {
 
     let options = options().fallback_to_usage().run();
 
-    let (_lock_file, command, command_args, env) = match launch_or_lock(
+    let launch_result = launch_or_lock(
         options.server_port,
         options.command,
         options.command_args,
         options.env,
-    )? {
-        LaunchResult::LaunchRequested => {
+        options.env_file.as_deref(),
+        LaunchOptions {
+            cwd: None,
+            timeout: options.timeout,
+            idle_timeout: options.idle_timeout,
+            user: options.user,
+            dry_run: options.dry_run,
+            detach: options.detach,
+            argv0: options.argv0,
+            fail_if_busy: options.fail_if_busy,
+            clean_env: options.clean_env,
+            expand_args: options.expand_args,
+            strict: options.strict,
+            cancel: None,
+            // A piped stdin is streamed rather than buffered, see
+            // `LaunchOptions::stdin`.
+            stdin: vec![],
+            capabilities: options.capabilities,
+            server_umask: options.server_umask,
+            stdout_path: options.stdout_path,
+            stderr_path: options.stderr_path,
+            append_output: options.append_output,
+            merge_stderr: options.merge_stderr,
+            forward_signals: true,
+        },
+    );
+    if let Err(err) = &launch_result {
+        // Exit as the signal we gave up on the command for would have made us.
+        if let Some(LaunchError::Interrupted(signal)) = err.downcast_ref() {
+            process::exit(128 + *signal as i32);
+        }
+        // Lets editors and other tools parse why the launch failed.
+        if env::var_os("KRUN_OUTPUT").is_some_and(|output| output == "json") {
+            eprintln!("{}", launch_error_json(err));
+            process::exit(1);
+        }
+    }
+
+    let (_lock_file, command, command_args, env) = match launch_result? {
+        LaunchResult::LaunchRequested { exit_code } => {
             // There was a krun instance already running and we've requested it
             // to launch the command successfully, so all the work is done.
+            process::exit(exit_code);
+        },
+        LaunchResult::DryRun => return Ok(()),
+        LaunchResult::Detached { id } => {
+            println!("{id}");
             return Ok(());
         },
         LaunchResult::LockAcquired {
             lock_file,
+            reason,
             command,
             command_args,
             env,
-        } => (lock_file, command, command_args, env),
+        } => {
+            debug!(reason:?; "starting microVM");
+            if let Some(fd) = options.port_fd {
+                write_port_fd(fd, options.server_port)?;
+            }
+            (lock_file, command, command_args, env)
+        },
     };
 
     {
@@ -196,6 +250,29 @@ fn main() -> Result<()> {
         }
     }
 
+    let forwarded_sockets = resolve_forwarded_sockets(&options.forward_sockets)?;
+    for socket in &forwarded_sockets {
+        let socket_path = CString::new(socket.host_path.to_str().with_context(|| {
+            format!(
+                "Failed to process `{}` path as it contains invalid UTF-8",
+                socket.env_var
+            )
+        })?)
+        .with_context(|| {
+            format!(
+                "Failed to process `{}` path as it contains NUL character",
+                socket.env_var
+            )
+        })?;
+        // SAFETY: `socket_path` is a pointer to a `CString` with long enough lifetime.
+        let err = unsafe { krun_add_vsock_port(ctx_id, socket.port, socket_path.as_ptr()) };
+        if err < 0 {
+            let err = Errno::from_raw_os_error(-err);
+            return Err(err)
+                .with_context(|| format!("Failed to configure vsock for `{}`", socket.env_var));
+        }
+    }
+
     // Forward the native X11 display into the guest as a socket
     if let Ok(x11_display) = env::var("DISPLAY") {
         if let Some(x11_display) = x11_display.strip_prefix(":") {
@@ -292,6 +369,10 @@ fn main() -> Result<()> {
         "KRUN_SERVER_PORT".to_owned(),
         options.server_port.to_string(),
     );
+    if !forwarded_sockets.is_empty() {
+        let (key, value) = forwarded_sockets_env(&forwarded_sockets);
+        env.insert(key, value);
+    }
     let env: Vec<CString> = {
         let mut vec = Vec::with_capacity(env.len());
         for (key, value) in env {
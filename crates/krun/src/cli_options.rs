@@ -1,18 +1,41 @@
 use std::ops::Range;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::{anyhow, Context};
 use bpaf::{any, construct, long, positional, OptionParser, Parser};
+use utils::launch::Capabilities;
 
+use crate::env::EnvValue;
+use crate::launch::DEFAULT_SERVER_PORT;
 use crate::types::MiB;
 
 #[derive(Clone, Debug)]
 pub struct Options {
     pub cpu_list: Vec<Range<u16>>,
-    pub env: Vec<(String, Option<String>)>,
+    pub env: Vec<(String, EnvValue)>,
+    pub env_file: Option<PathBuf>,
     pub mem: Option<MiB>,
     pub passt_socket: Option<PathBuf>,
     pub server_port: u32,
+    pub port_fd: Option<i32>,
+    pub timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub user: Option<String>,
+    pub detach: bool,
+    pub argv0: Option<String>,
+    pub fail_if_busy: bool,
+    pub clean_env: bool,
+    pub expand_args: bool,
+    pub strict: bool,
+    pub capabilities: Option<Capabilities>,
+    pub server_umask: bool,
+    pub stdout_path: Option<PathBuf>,
+    pub stderr_path: Option<PathBuf>,
+    pub append_output: bool,
+    pub merge_stderr: bool,
+    pub forward_sockets: Vec<String>,
+    pub dry_run: bool,
     pub command: PathBuf,
     pub command_args: Vec<String>,
 }
@@ -45,15 +68,29 @@ pub fn options() -> OptionParser<Options> {
         .help(
             "Set environment variable to be passed to the microVM
             ENV should be in KEY=VALUE format, or KEY on its own to inherit
-            the current value from the local environment",
+            the current value from the local environment, or KEY? to inherit
+            it only if it is set in the local environment. For PATH and other
+            lists of directories, KEY=+VALUE prepends VALUE to the forwarded
+            value, and KEY+=VALUE appends it",
         )
         .argument::<String>("ENV")
         .parse(|s| match s.split_once('=') {
             Some(("", _)) => Err(anyhow!("invalid ENV format")),
-            Some((k, v)) => Ok((k.to_owned(), Some(v.to_owned()))),
-            None => Ok((s, None)),
+            Some((k, v)) => Ok((k.to_owned(), EnvValue::Set(v.to_owned()))),
+            None => match s.strip_suffix('?') {
+                Some("") => Err(anyhow!("invalid ENV format")),
+                Some(k) => Ok((k.to_owned(), EnvValue::InheritIfSet)),
+                None => Ok((s, EnvValue::Inherit)),
+            },
         })
         .many();
+    let env_file = long("env-file")
+        .help(
+            "Read environment variables to be passed to the microVM from PATH,
+            with one KEY=VALUE per line, overridden by those set with --env",
+        )
+        .argument("PATH")
+        .optional();
     let mem = long("mem")
         .help(
             "The amount of RAM, in MiB, that will be available to this microVM.
@@ -78,8 +115,145 @@ pub fn options() -> OptionParser<Options> {
         .short('p')
         .help("Set the port to be used in server mode")
         .argument("SERVER_PORT")
-        .fallback(3334)
+        .fallback(DEFAULT_SERVER_PORT)
         .display_fallback();
+    let port_fd = long("port-fd")
+        .help(
+            "When starting the microVM, write the server port as a line to FD, an
+            inherited file descriptor such as the write end of a pipe, and close
+            it. krun run with KRUN_PORT_FD set to the read end then launches
+            commands there without looking for the lock file",
+        )
+        .argument::<i32>("FD")
+        .guard(
+            |fd| *fd > 2,
+            "FD must be an inherited file descriptor above 2",
+        )
+        .optional();
+    let timeout = long("timeout")
+        .help(
+            "Kill COMMAND if it runs for longer than SECS seconds in a running
+            microVM, exiting with status 124",
+        )
+        .argument::<u64>("SECS")
+        .guard(|secs| *secs > 0, "SECS must be greater than 0")
+        .map(Duration::from_secs)
+        .optional();
+    let idle_timeout = long("idle-timeout")
+        .help(
+            "Kill COMMAND in a running microVM if krun sends no heartbeat for SECS
+            seconds, e.g. because it was killed but its connection was left open.
+            A suspended krun, e.g. after Ctrl-Z, sends none either. Without this,
+            COMMAND is only killed once the connection to krun is closed",
+        )
+        .argument::<u64>("SECS")
+        .guard(
+            |secs| *secs >= 15,
+            "SECS must be at least 15, that is 3 heartbeat intervals",
+        )
+        .map(Duration::from_secs)
+        .optional();
+    let user = long("user")
+        .help(
+            "Run COMMAND as USER, a user name or uid in the guest, in a running
+            microVM",
+        )
+        .argument::<String>("USER")
+        .optional();
+    let detach = long("detach")
+        .help(
+            "Leave COMMAND running in a running microVM and print its launch id,
+            instead of waiting for it to finish. Its output is logged to a file
+            in the runtime directory of the user in the microVM",
+        )
+        .switch();
+    let argv0 = long("argv0")
+        .help(
+            "Run COMMAND with NAME as argv[0], instead of its base name, in a running
+            microVM",
+        )
+        .argument::<String>("NAME")
+        .optional();
+    let fail_if_busy = long("fail-if-busy")
+        .help(
+            "Fail instead of waiting if a running microVM already runs as many
+            commands as it allows",
+        )
+        .switch();
+    let clean_env = long("clean-env")
+        .help(
+            "Run the command with only the env vars passed to it, and PATH, HOME
+            and TERM from the microVM, instead of the whole environment of the
+            microVM",
+        )
+        .switch();
+    let expand_args = long("expand-args")
+        .help(
+            "Expand ${VAR} in COMMAND_ARGS to the value of the env var VAR, or
+            ${VAR:-DEFAULT} to DEFAULT if VAR is unset or empty. $$ stands for a
+            literal $",
+        )
+        .switch();
+    let strict = long("strict")
+        .help(
+            "Fail instead of warning if COMMAND is a path that doesn't exist on the
+            host",
+        )
+        .switch();
+    let drop_caps = long("drop-caps")
+        .help(
+            "Take the Linux capabilities in CAPS, a comma-separated list such as
+            NET_ADMIN,SYS_ADMIN, away from COMMAND",
+        )
+        .argument::<String>("CAPS")
+        .map(|caps| Capabilities::Drop(split_capabilities(&caps)));
+    let keep_caps = long("keep-caps")
+        .help(
+            "Take every Linux capability but those in CAPS, a comma-separated list,
+            away from COMMAND",
+        )
+        .argument::<String>("CAPS")
+        .map(|caps| Capabilities::Keep(split_capabilities(&caps)));
+    let capabilities = construct!([drop_caps, keep_caps]).optional();
+    let server_umask = long("server-umask")
+        .help(
+            "Run COMMAND with the umask of the krun server in a running microVM,
+            instead of the umask of krun",
+        )
+        .switch();
+    let stdout_path = long("stdout")
+        .help(
+            "Write the stdout of COMMAND to FILE in the microVM, e.g. /dev/null,
+            instead of printing it. FILE is truncated unless --append-output is
+            given",
+        )
+        .argument::<PathBuf>("FILE")
+        .optional();
+    let stderr_path = long("stderr")
+        .help("Write the stderr of COMMAND to FILE in the microVM, as with --stdout")
+        .argument::<PathBuf>("FILE")
+        .optional();
+    let append_output = long("append-output")
+        .help("Append to the files given with --stdout and --stderr")
+        .switch();
+    let merge_stderr = long("merge-stderr")
+        .help("Send the stderr of COMMAND wherever its stdout goes, as with 2>&1")
+        .switch();
+    let forward_sockets = long("forward-socket")
+        .help(
+            "Forward the host socket whose path is in the env var VAR, such as
+            SSH_AUTH_SOCK, to the microVM, and set VAR there to where it can be
+            reached. Only applies when starting the microVM. SSH_AUTH_SOCK is
+            forwarded anyway if it is a socket, unless KRUN_NO_SSH_AGENT is set",
+        )
+        .argument::<String>("VAR")
+        .many();
+    let dry_run = long("dry-run")
+        .help(
+            "Print the launch request that would be sent to the krun server as
+            JSON, instead of running COMMAND",
+        )
+        .switch();
     let command = positional("COMMAND").help("the command you want to execute in the vm");
     let command_args = any::<String, _, _>("COMMAND_ARGS", |arg| {
         (!["--help", "-h"].contains(&&*arg)).then_some(arg)
@@ -90,9 +264,28 @@ pub fn options() -> OptionParser<Options> {
     construct!(Options {
         cpu_list,
         env,
+        env_file,
         mem,
         passt_socket,
         server_port,
+        port_fd,
+        timeout,
+        idle_timeout,
+        user,
+        detach,
+        argv0,
+        fail_if_busy,
+        clean_env,
+        expand_args,
+        strict,
+        capabilities,
+        server_umask,
+        stdout_path,
+        stderr_path,
+        append_output,
+        merge_stderr,
+        forward_sockets,
+        dry_run,
         // positionals
         command,
         command_args,
@@ -100,6 +293,14 @@ pub fn options() -> OptionParser<Options> {
     .to_options()
 }
 
+fn split_capabilities(caps: &str) -> Vec<String> {
+    caps.split(',')
+        .map(str::trim)
+        .filter(|cap| !cap.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -108,4 +309,21 @@ mod tests {
     fn check_options() {
         options().check_invariants(false)
     }
+
+    #[test]
+    fn parse_env_options() {
+        let parsed = options()
+            .run_inner(&["-e", "A=1", "-e", "B", "-e", "C?", "-e", "D=", "true"])
+            .unwrap();
+        assert_eq!(
+            parsed.env,
+            vec![
+                ("A".to_owned(), EnvValue::Set("1".to_owned())),
+                ("B".to_owned(), EnvValue::Inherit),
+                ("C".to_owned(), EnvValue::InheritIfSet),
+                ("D".to_owned(), EnvValue::Set("".to_owned())),
+            ]
+        );
+        assert!(options().run_inner(&["-e", "?", "true"]).is_err());
+    }
 }
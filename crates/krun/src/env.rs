@@ -1,86 +1,813 @@
 use std::collections::HashMap;
 use std::env::{self, VarError};
-use std::ffi::CString;
+use std::ffi::{CString, OsStr};
+use std::fmt;
 use std::fs;
 use std::io::ErrorKind;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::fs::FileTypeExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use log::debug;
-use utils::env::find_in_path;
+use utils::env::{find_in_path, FORWARDED_SOCKETS_ENV_VAR, FORWARDED_SOCKET_BASE_PORT};
+use utils::fs::find_executable;
 
 /// Automatically pass these environment variables to the microVM, if they are
 /// set.
-const WELL_KNOWN_ENV_VARS: [&str; 5] = [
+const WELL_KNOWN_ENV_VARS: [&str; 8] = [
+    "KRUN_SOCAT",     // read by `krun-guest` to forward sockets
+    "KRUN_VSOCK_CID", // read by `krun-guest` to forward sockets
     "LD_LIBRARY_PATH",
     "LIBGL_DRIVERS_PATH",
     "MESA_LOADER_DRIVER_OVERRIDE", // needed for asahi
     "PATH",                        // needed by `krun-guest` program
     "RUST_LOG",
+    "XDG_SESSION_TYPE",
 ];
 
-/// See https://github.com/AsahiLinux/docs/wiki/Devices
-const ASAHI_SOC_COMPAT_IDS: [&str; 1] = ["apple,arm-platform"];
+/// Locale environment variables passed to the microVM, if they are set, unless
+/// `LC_ALL` is set and overrides them.
+const LOCALE_ENV_VARS: [&str; 14] = [
+    "LANG",
+    "LANGUAGE",
+    "LC_ADDRESS",
+    "LC_COLLATE",
+    "LC_CTYPE",
+    "LC_IDENTIFICATION",
+    "LC_MEASUREMENT",
+    "LC_MESSAGES",
+    "LC_MONETARY",
+    "LC_NAME",
+    "LC_NUMERIC",
+    "LC_PAPER",
+    "LC_TELEPHONE",
+    "LC_TIME",
+];
+
+/// Colon-separated lists of directories, to which a value passed to the
+/// microVM can add rather than replace what is forwarded: `+DIR` prepends
+/// `DIR`, and a `KEY+` variable appends its value to `KEY`.
+const PATH_LIKE_ENV_VARS: [&str; 7] = [
+    "LD_LIBRARY_PATH",
+    "LIBGL_DRIVERS_PATH",
+    "MANPATH",
+    "PATH",
+    "PKG_CONFIG_PATH",
+    "XDG_CONFIG_DIRS",
+    "XDG_DATA_DIRS",
+];
+
+/// Set in the environment of everything running in the microVM.
+pub const INSIDE_VM_ENV_VAR: &str = "KRUN_INSIDE_VM";
+
+/// Values of these environment variables are not logged, unless overridden
+/// with a comma-separated list of patterns in `KRUN_SENSITIVE_ENV_VARS`. A `*`
+/// at the start or end of a pattern matches anything.
+const SENSITIVE_ENV_VARS: [&str; 4] = ["*TOKEN*", "*SECRET*", "*PASSWORD*", "XAUTHORITY"];
+
+/// Maximum total size, in bytes, of the env vars passed to the microVM, unless
+/// overridden with `KRUN_ENV_MAX_SIZE`.
+const ENV_MAX_SIZE: usize = 1024 * 1024;
+
+/// Mesa driver override to use for SoCs, by device-tree compatible id. The
+/// first matching compatible id of the device tree wins.
+const SOC_MESA_DRIVERS: &[(&str, &str)] = &[
+    // See https://github.com/AsahiLinux/docs/wiki/Devices
+    ("apple,arm-platform", "asahi"),
+];
+
+/// Value of an environment variable requested to be passed to the microVM.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EnvValue {
+    /// Set the variable to this value.
+    Set(String),
+    /// Inherit the current value from the local environment, where it must be
+    /// set.
+    Inherit,
+    /// Inherit the current value from the local environment, if it is set
+    /// there. Otherwise the variable is skipped.
+    InheritIfSet,
+}
+
+/// Returns the env vars always passed to the microVM if they are set, on top of
+/// those named in `KRUN_PASSTHROUGH`, the host locale and the displays.
+pub fn well_known_env_vars() -> &'static [&'static str] {
+    &WELL_KNOWN_ENV_VARS
+}
+
+/// A host socket forwarded to the microVM with `--forward-socket`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ForwardedSocket {
+    /// Env var holding the path of the socket, on the host and in the microVM.
+    pub env_var: String,
+    pub host_path: PathBuf,
+    /// vsock port through which the microVM reaches the socket.
+    pub port: u32,
+}
+
+/// Env var holding the SSH agent socket, which is forwarded to the microVM
+/// whenever there is one, so that e.g. `git push` over SSH works there.
+const SSH_AUTH_SOCK_ENV_VAR: &str = "SSH_AUTH_SOCK";
+
+/// Looks up the sockets named by `env_vars`, such as `SSH_AUTH_SOCK`, to be
+/// forwarded to the microVM when starting it, each on its own vsock port. The
+/// SSH agent socket is forwarded too if there is one, unless `KRUN_NO_FORWARD`
+/// or, to only keep the SSH agent out of the microVM, `KRUN_NO_SSH_AGENT` is
+/// set.
+pub fn resolve_forwarded_sockets(env_vars: &[String]) -> Result<Vec<ForwardedSocket>> {
+    let mut env_vars = env_vars.to_vec();
+    if !env_vars
+        .iter()
+        .any(|env_var| env_var == SSH_AUTH_SOCK_ENV_VAR)
+        && env::var_os("KRUN_NO_FORWARD").is_none()
+        && env::var_os("KRUN_NO_SSH_AGENT").is_none()
+    {
+        match env::var_os(SSH_AUTH_SOCK_ENV_VAR) {
+            Some(path) if is_socket(Path::new(&path)) => {
+                env_vars.push(SSH_AUTH_SOCK_ENV_VAR.to_owned());
+            },
+            path => debug!(path:?; "no SSH agent socket to forward"),
+        }
+    }
+    env_vars
+        .iter()
+        .zip(FORWARDED_SOCKET_BASE_PORT..)
+        .map(|(env_var, port)| {
+            if env_var.is_empty() || env_var.contains([',', ':', '=']) {
+                return Err(anyhow!(
+                    "Invalid env var name {env_var:?} of socket to forward"
+                ));
+            }
+            let host_path = env::var_os(env_var)
+                .map(PathBuf::from)
+                .with_context(|| format!("`{env_var}` is not set, no socket to forward"))?;
+            if !is_socket(&host_path) {
+                return Err(anyhow!(
+                    "`{env_var}` {host_path:?} is not a socket, only sockets can be forwarded"
+                ));
+            }
+            Ok(ForwardedSocket {
+                env_var: env_var.to_owned(),
+                host_path,
+                port,
+            })
+        })
+        .collect()
+}
+
+fn is_socket(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+}
+
+/// Returns the env var telling krun-guest which sockets to proxy, see
+/// [`FORWARDED_SOCKETS_ENV_VAR`].
+pub fn forwarded_sockets_env(sockets: &[ForwardedSocket]) -> (String, String) {
+    let value = sockets
+        .iter()
+        .map(|socket| format!("{}:{}", socket.env_var, socket.port))
+        .collect::<Vec<_>>()
+        .join(",");
+    (FORWARDED_SOCKETS_ENV_VAR.to_owned(), value)
+}
 
-pub fn prepare_env_vars(env: Vec<(String, Option<String>)>) -> Result<HashMap<String, String>> {
+/// Prepares the env vars to pass to the microVM, as [`resolve_vm_env`], then
+/// logs and validates them.
+pub fn prepare_env_vars(env: Vec<(String, EnvValue)>) -> Result<HashMap<String, String>> {
+    let env_map = resolve_vm_env(env)?;
+
+    debug!(env:? = RedactedEnv(&env_map, &sensitive_env_vars()); "env vars");
+
+    validate_env_vars(&env_map, env_max_size()?)?;
+
+    Ok(env_map)
+}
+
+/// Returns the env vars that would be passed to the microVM given the current
+/// environment: the well-known and `KRUN_PASSTHROUGH` ones, the host locale
+/// and timezone, `extra` and those describing the host displays, rewritten
+/// by the rules in `KRUN_ENV_REWRITE` if any. With `KRUN_NO_FORWARD` set, only
+/// `extra` is forwarded. Nothing is changed, this only reads the environment,
+/// the device tree and `/etc/localtime`, so it can be used to inspect what a
+/// launch would forward.
+pub fn resolve_vm_env(extra: Vec<(String, EnvValue)>) -> Result<HashMap<String, String>> {
     let mut env_map = HashMap::new();
 
-    for key in WELL_KNOWN_ENV_VARS {
+    // `KRUN_NO_FORWARD` leaves out everything taken from the host environment
+    // unless asked for in `extra`, e.g. for reproducible builds.
+    let forward = env::var_os("KRUN_NO_FORWARD").is_none();
+    if forward {
+        forward_host_env_vars(&mut env_map, passthrough_env_vars())?;
+    }
+
+    for (key, value) in extra {
+        let value = match value {
+            EnvValue::Set(value) => value,
+            EnvValue::Inherit => {
+                env::var(&key).with_context(|| format!("Failed to get `{key}` env var"))?
+            },
+            EnvValue::InheritIfSet => match env::var(&key) {
+                Ok(value) => value,
+                Err(VarError::NotPresent) => continue,
+                Err(err) => Err(err).with_context(|| format!("Failed to get `{key}` env var"))?,
+            },
+        };
+        let (key, value) = merge_path_like(&env_map, key, value);
+        env_map.insert(key, value);
+    }
+
+    if forward {
+        forward_host_displays(&mut env_map)?;
+    }
+
+    if let Ok(rules) = env::var("KRUN_ENV_REWRITE") {
+        rewrite_env_vars(&mut env_map, &parse_env_rewrites(&rules)?);
+    }
+
+    // Lets krun refuse to run inside the microVM, instead of requesting the
+    // server to launch commands recursively.
+    env_map.insert(INSIDE_VM_ENV_VAR.to_owned(), "1".to_owned());
+
+    Ok(env_map)
+}
+
+/// Merges `value` into the current value of a path-like variable if asked to,
+/// see [`PATH_LIKE_ENV_VARS`], returning the variable to set and its value.
+fn merge_path_like(
+    env_map: &HashMap<String, String>,
+    key: String,
+    value: String,
+) -> (String, String) {
+    let is_path_like = |key: &str| PATH_LIKE_ENV_VARS.contains(&key);
+    let join = |first: &str, second: &str| match (first, second) {
+        ("", dirs) | (dirs, "") => dirs.to_owned(),
+        (first, second) => format!("{first}:{second}"),
+    };
+    if let Some(base) = key.strip_suffix('+').filter(|base| is_path_like(base)) {
+        let current = env_map.get(base).map_or("", String::as_str);
+        return (base.to_owned(), join(current, &value));
+    }
+    match value.strip_prefix('+') {
+        Some(dirs) if is_path_like(&key) => {
+            let current = env_map.get(&key).map_or("", String::as_str);
+            let value = join(dirs, current);
+            (key, value)
+        },
+        _ => (key, value),
+    }
+}
+
+/// Returns the additional env vars to pass to the microVM, named in
+/// `KRUN_PASSTHROUGH` as a comma-separated list. The result is cached, as it's
+/// only meant to be set when starting krun.
+fn passthrough_env_vars() -> &'static [String] {
+    static PASSTHROUGH_ENV_VARS: OnceLock<Vec<String>> = OnceLock::new();
+
+    PASSTHROUGH_ENV_VARS.get_or_init(|| {
+        env::var("KRUN_PASSTHROUGH")
+            .map(|names| parse_passthrough_env_vars(&names))
+            .unwrap_or_default()
+    })
+}
+
+fn parse_passthrough_env_vars(names: &str) -> Vec<String> {
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Forwards the well-known env vars and `passthrough_env_vars`, the host
+/// locale and timezone.
+fn forward_host_env_vars(
+    env_map: &mut HashMap<String, String>,
+    passthrough_env_vars: &[String],
+) -> Result<()> {
+    for key in WELL_KNOWN_ENV_VARS
+        .into_iter()
+        .chain(passthrough_env_vars.iter().map(String::as_str))
+    {
         let value = match env::var(key) {
             Ok(value) => value,
             Err(VarError::NotPresent) => {
                 if key == "MESA_LOADER_DRIVER_OVERRIDE" {
-                    match fs::read_to_string("/proc/device-tree/compatible") {
-                        Ok(compatible) => {
-                            for compat_id in compatible.split('\0') {
-                                if ASAHI_SOC_COMPAT_IDS.iter().any(|&s| s == compat_id) {
-                                    env_map.insert(
-                                        "MESA_LOADER_DRIVER_OVERRIDE".to_owned(),
-                                        "asahi".to_owned(),
-                                    );
-                                    break;
-                                }
-                            }
-                        },
-                        Err(err) if err.kind() == ErrorKind::NotFound => {
-                            continue;
-                        },
-                        Err(err) => {
-                            Err(err).context("Failed to read `/proc/device-tree/compatible`")?
-                        },
+                    if let Some(driver) = soc_mesa_driver() {
+                        env_map.insert(key.to_owned(), driver.to_owned());
                     }
                 }
                 continue;
             },
-            Err(err) => Err(err).with_context(|| format!("Failed to get `{key}` env var"))?,
+            Err(VarError::NotUnicode(_)) => {
+                return Err(anyhow!(
+                    "Failed to get `{key}` env var: its value is not valid UTF-8"
+                ));
+            },
         };
         env_map.insert(key.to_owned(), value);
     }
 
-    for (key, value) in env {
-        let value = value.map_or_else(
-            || env::var(&key).with_context(|| format!("Failed to get `{key}` env var")),
-            Ok,
-        )?;
-        env_map.insert(key, value);
+    // The host `PATH` is forwarded verbatim, unless asked to normalize it.
+    if let Some(path) = env_map.get_mut("PATH") {
+        let prefix = env::var("KRUN_PATH_PREFIX").ok();
+        if env::var_os("KRUN_NORMALIZE_PATH").is_some() || prefix.is_some() {
+            *path = normalize_path(prefix.as_deref(), path, |dir| dir.is_dir());
+        }
+    }
+
+    forward_locale_env_vars(env_map)?;
+
+    if let Some(tz) = host_timezone(Path::new("/etc/localtime"))? {
+        env_map.insert("TZ".to_owned(), tz);
     }
 
+    Ok(())
+}
+
+/// Forwards the env vars describing the host displays.
+fn forward_host_displays(env_map: &mut HashMap<String, String>) -> Result<()> {
     // If we have an X11 display in the host, set HOST_DISPLAY in the guest.
     // krun-guest will then use this to set up xauth and replace it with :1
-    // (which is forwarded to the host display).
+    // (which is forwarded to the host display). Where the guest can reach the
+    // host X server directly, `KRUN_X11_PASSTHROUGH=direct` forwards DISPLAY
+    // as is instead.
+    let direct_x11 = match env::var("KRUN_X11_PASSTHROUGH") {
+        Ok(mode) if mode == "direct" => true,
+        Ok(mode) => {
+            return Err(anyhow!(
+                "Invalid `KRUN_X11_PASSTHROUGH` value {mode:?}, the only mode is `direct`"
+            ));
+        },
+        Err(_) => false,
+    };
     if let Ok(display) = env::var("DISPLAY") {
-        env_map.insert("HOST_DISPLAY".to_string(), display);
+        let key = if direct_x11 {
+            "DISPLAY"
+        } else {
+            "HOST_DISPLAY"
+        };
+        env_map.insert(key.to_string(), display);
 
         // And forward XAUTHORITY. This will be modified to fix the
         // display name in krun-guest.
-        if let Ok(xauthority) = env::var("XAUTHORITY") {
+        if let Some(xauthority) = xauthority_path() {
             env_map.insert("XAUTHORITY".to_string(), xauthority);
         }
     }
 
-    debug!(env:? = env_map; "env vars");
+    // If we have a Wayland display in the host, set HOST_WAYLAND_DISPLAY in the
+    // guest to the path of the compositor socket. This is independent of X11,
+    // so both may be set. krun-guest still needs to forward the socket for
+    // anything in the guest to be able to connect to it.
+    if let Ok(wayland_display) = env::var("WAYLAND_DISPLAY") {
+        let socket_path = if Path::new(&wayland_display).is_absolute() {
+            Some(PathBuf::from(wayland_display))
+        } else {
+            env::var("XDG_RUNTIME_DIR")
+                .ok()
+                .map(|run_path| Path::new(&run_path).join(wayland_display))
+        };
+        if let Some(socket_path) = socket_path.as_ref().and_then(|p| p.to_str()) {
+            env_map.insert("HOST_WAYLAND_DISPLAY".to_owned(), socket_path.to_owned());
+        }
+    }
+
+    Ok(())
+}
 
-    Ok(env_map)
+/// Returns the X authority file to forward, which must be readable for krun-guest
+/// to set up xauth. Display managers may leave `XAUTHORITY` pointing at an
+/// ephemeral file that's gone, in which case `~/.Xauthority` is tried instead.
+fn xauthority_path() -> Option<String> {
+    let readable = |path: &str| fs::File::open(path).is_ok();
+    if let Ok(xauthority) = env::var("XAUTHORITY") {
+        if readable(&xauthority) {
+            return Some(xauthority);
+        }
+        debug!(xauthority:%; "XAUTHORITY file is not readable");
+    }
+    let home = env::var("HOME").ok()?;
+    let fallback = Path::new(&home).join(".Xauthority");
+    let fallback = fallback.to_str()?;
+    if readable(fallback) {
+        return Some(fallback.to_owned());
+    }
+    debug!("no readable X authority file, not forwarding XAUTHORITY");
+    None
+}
+
+/// Prepends `prefix` to `path` and removes the empty, duplicate and missing
+/// directories from both. The microVM uses the host root filesystem, so
+/// directories missing on the host are most likely missing in the guest too,
+/// as decided by `exists`.
+fn normalize_path<F>(prefix: Option<&str>, path: &str, exists: F) -> String
+where
+    F: Fn(&Path) -> bool,
+{
+    let mut dirs: Vec<&str> = vec![];
+    for dir in prefix.into_iter().chain([path]).flat_map(|p| p.split(':')) {
+        if !dir.is_empty() && !dirs.contains(&dir) && exists(Path::new(dir)) {
+            dirs.push(dir);
+        }
+    }
+    dirs.join(":")
+}
+
+/// A rule of `KRUN_ENV_REWRITE` replacing `from` with `to` in the value of the
+/// env var `key`, for values that differ between the host and the guest.
+#[derive(Clone, Eq, PartialEq, Debug)]
+struct EnvRewrite {
+    key: String,
+    from: String,
+    to: String,
+    /// Whether `from` only has to start the value, rather than be all of it.
+    prefix: bool,
+}
+
+/// Parses comma-separated `KEY:FROM=>TO` rules. A `FROM` ending with `*`
+/// matches the values it starts, e.g. `HOME:/host/home/*=>/home/` rewrites
+/// `/host/home/user` to `/home/user`.
+fn parse_env_rewrites(rules: &str) -> Result<Vec<EnvRewrite>> {
+    rules
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let invalid = || anyhow!("Invalid `KRUN_ENV_REWRITE` rule {rule:?}");
+            let (key, rewrite) = rule.split_once(':').ok_or_else(invalid)?;
+            let (from, to) = rewrite.split_once("=>").ok_or_else(invalid)?;
+            if key.is_empty() || from.is_empty() {
+                return Err(invalid());
+            }
+            let (from, prefix) = match from.strip_suffix('*') {
+                Some(from) => (from, true),
+                None => (from, false),
+            };
+            Ok(EnvRewrite {
+                key: key.to_owned(),
+                from: from.to_owned(),
+                to: to.to_owned(),
+                prefix,
+            })
+        })
+        .collect()
+}
+
+/// Applies `rules` in order to the env vars in `env_map`, leaving the values
+/// they don't match alone.
+fn rewrite_env_vars(env_map: &mut HashMap<String, String>, rules: &[EnvRewrite]) {
+    for rule in rules {
+        let Some(value) = env_map.get_mut(&rule.key) else {
+            continue;
+        };
+        let rest = if rule.prefix {
+            value.strip_prefix(&rule.from)
+        } else {
+            (*value == rule.from).then_some("")
+        };
+        if let Some(rest) = rest {
+            *value = format!("{}{rest}", rule.to);
+        }
+    }
+}
+
+/// Forwards the locale of the host, so that guest programs don't fall back to
+/// the C locale. A non-empty `LC_ALL` overrides `LANG` and every other `LC_*`
+/// variable, so only it is forwarded then. `LANGUAGE` takes precedence over
+/// `LC_ALL` for messages, so it is always forwarded. Empty variables are
+/// ignored, as they would be by the C library.
+fn forward_locale_env_vars(env_map: &mut HashMap<String, String>) -> Result<()> {
+    let lc_all = non_empty_env_var("LC_ALL")?;
+    let keys: &[&str] = if lc_all.is_some() {
+        &["LC_ALL", "LANGUAGE"]
+    } else {
+        &LOCALE_ENV_VARS
+    };
+    for &key in keys {
+        if let Some(value) = non_empty_env_var(key)? {
+            env_map.insert(key.to_owned(), value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the timezone of the host, so that guest programs don't fall back to
+/// UTC: `TZ` if set, otherwise the zone `localtime` links to in a `zoneinfo`
+/// directory, if it does.
+fn host_timezone(localtime: &Path) -> Result<Option<String>> {
+    match env::var("TZ") {
+        Ok(tz) => return Ok(Some(tz)),
+        Err(VarError::NotPresent) => {},
+        Err(err) => return Err(err).context("Failed to get `TZ` env var"),
+    }
+
+    let Ok(target) = fs::read_link(localtime) else {
+        return Ok(None);
+    };
+    let target = target.to_str().unwrap_or_default();
+    let zone = target
+        .rsplit_once("zoneinfo/")
+        .map(|(_, zone)| zone)
+        .filter(|zone| !zone.is_empty());
+    Ok(zone.map(str::to_owned))
+}
+
+fn non_empty_env_var(key: &str) -> Result<Option<String>> {
+    match env::var(key) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Failed to get `{key}` env var")),
+    }
+}
+
+/// Reads env vars from a dotenv-style file, with one `KEY=VALUE` per line.
+/// Values may be quoted with single quotes, taken literally, or double quotes,
+/// in which `\"`, `\\` and `\n` are unescaped. Blank lines and lines starting
+/// with `#` are ignored.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, EnvValue)>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read env file {path:?}"))?;
+    parse_env_file(&contents).with_context(|| format!("Failed to parse env file {path:?}"))
+}
+
+fn parse_env_file(contents: &str) -> Result<Vec<(String, EnvValue)>> {
+    let mut env = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: expected KEY=VALUE", i + 1))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(anyhow!("line {}: invalid env var name {key:?}", i + 1));
+        }
+        let value =
+            parse_env_file_value(value.trim()).with_context(|| format!("line {}", i + 1))?;
+        env.push((key.to_owned(), EnvValue::Set(value)));
+    }
+    Ok(env)
+}
+
+fn parse_env_file_value(value: &str) -> Result<String> {
+    if let Some(quoted) = value.strip_prefix('\'') {
+        return quoted
+            .strip_suffix('\'')
+            .map(str::to_owned)
+            .context("unterminated single quote");
+    }
+    let Some(quoted) = value.strip_prefix('"') else {
+        return Ok(value.to_owned());
+    };
+
+    let mut unquoted = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if chars.as_str().is_empty() => return Ok(unquoted),
+            '"' => return Err(anyhow!("unexpected characters after closing quote")),
+            '\\' => match chars.next() {
+                Some('n') => unquoted.push('\n'),
+                Some(c @ ('"' | '\\')) => unquoted.push(c),
+                Some(c) => {
+                    unquoted.push('\\');
+                    unquoted.push(c);
+                },
+                None => break,
+            },
+            c => unquoted.push(c),
+        }
+    }
+    Err(anyhow!("unterminated double quote"))
+}
+
+/// Expands `${VAR}` in `value` to the value of the host env var `VAR`, which
+/// must be set, and `${VAR:-default}` to `default` if `VAR` is unset or empty.
+/// `$$` stands for a literal `$`, and a `$` followed by anything else is kept
+/// as is.
+pub fn expand_env_vars(value: &str) -> Result<String> {
+    expand_vars(value, |key| match env::var(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Failed to get `{key}` env var")),
+    })
+}
+
+fn expand_vars<F>(value: &str, lookup: F) -> Result<String>
+where
+    F: Fn(&str) -> Result<Option<String>>,
+{
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix('{') else {
+            expanded.push('$');
+            continue;
+        };
+        let (var, after) = after
+            .split_once('}')
+            .with_context(|| format!("Unterminated `${{` in {value:?}"))?;
+        let (key, default) = match var.split_once(":-") {
+            Some((key, default)) => (key, Some(default)),
+            None => (var, None),
+        };
+        if key.is_empty() || key.contains(['=', '\0']) {
+            return Err(anyhow!("Invalid env var name {key:?} in {value:?}"));
+        }
+        match (lookup(key)?, default) {
+            (Some(value), Some(default)) if value.is_empty() => expanded.push_str(default),
+            (Some(value), _) => expanded.push_str(&value),
+            (None, Some(default)) => expanded.push_str(default),
+            (None, None) => {
+                return Err(anyhow!("Env var `{key}` in {value:?} is not set"));
+            },
+        }
+        rest = after;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Makes sure the env vars can be set for the command in the microVM, and that
+/// they don't add up to more than `max_size` bytes, as `KEY=VALUE\0` strings.
+fn validate_env_vars(env_map: &HashMap<String, String>, max_size: usize) -> Result<()> {
+    let mut size = 0;
+    for (key, value) in env_map {
+        validate_env_var_name(key)?;
+        if value.contains('\0') {
+            return Err(anyhow!(
+                "Invalid value for `{key}` env var, it contains a NUL byte"
+            ));
+        }
+        size += key.len() + value.len() + 2;
+    }
+    if size > max_size {
+        return Err(anyhow!(
+            "Env vars take up {size} bytes, more than the maximum of {max_size} bytes (see \
+             `KRUN_ENV_MAX_SIZE`)"
+        ));
+    }
+    Ok(())
+}
+
+/// Makes sure `key` can be the name of an env var of the command.
+pub(crate) fn validate_env_var_name(key: &str) -> Result<()> {
+    if key.is_empty() || key.contains(['=', '\0']) {
+        return Err(anyhow!("Invalid env var name {key:?}"));
+    }
+    Ok(())
+}
+
+fn env_max_size() -> Result<usize> {
+    match env::var("KRUN_ENV_MAX_SIZE") {
+        Ok(size) => size
+            .parse()
+            .with_context(|| format!("Failed to parse `KRUN_ENV_MAX_SIZE` value {size:?}")),
+        Err(_) => Ok(ENV_MAX_SIZE),
+    }
+}
+
+/// Replaces the values of the env vars matching the patterns of
+/// [`SENSITIVE_ENV_VARS`] or `KRUN_SENSITIVE_ENV_VARS` with `***`, the way they
+/// are left out of the logs.
+pub fn redact_env_values(env: &mut HashMap<String, String>) {
+    let sensitive_env_vars = sensitive_env_vars();
+    for (key, value) in env.iter_mut() {
+        if is_sensitive(key, &sensitive_env_vars) {
+            *value = "***".to_owned();
+        }
+    }
+}
+
+fn sensitive_env_vars() -> Vec<String> {
+    match env::var("KRUN_SENSITIVE_ENV_VARS") {
+        Ok(patterns) => patterns.split(',').map(|p| p.trim().to_owned()).collect(),
+        Err(_) => SENSITIVE_ENV_VARS.map(str::to_owned).to_vec(),
+    }
+}
+
+fn is_sensitive<S: AsRef<str>>(name: &str, patterns: &[S]) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| matches_pattern(name, pattern.as_ref()))
+}
+
+/// Formats env vars with the values of those matching any of the patterns
+/// replaced by `***`.
+struct RedactedEnv<'a, S>(&'a HashMap<String, String>, &'a [S]);
+
+impl<S: AsRef<str>> fmt::Debug for RedactedEnv<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut env: Vec<_> = self.0.iter().collect();
+        env.sort();
+        f.debug_map()
+            .entries(env.into_iter().map(|(key, value)| {
+                if is_sensitive(key, self.1) {
+                    (key, "***")
+                } else {
+                    (key, value.as_str())
+                }
+            }))
+            .finish()
+    }
+}
+
+fn matches_pattern(name: &str, pattern: &str) -> bool {
+    let (any_prefix, pattern) = pattern
+        .strip_prefix('*')
+        .map_or((false, pattern), |pattern| (true, pattern));
+    let (any_suffix, pattern) = pattern
+        .strip_suffix('*')
+        .map_or((false, pattern), |pattern| (true, pattern));
+    match (any_prefix, any_suffix) {
+        (true, true) => name.contains(pattern),
+        (true, false) => name.ends_with(pattern),
+        (false, true) => name.starts_with(pattern),
+        (false, false) => name == pattern,
+    }
+}
+
+/// Looks up the Mesa driver override for the SoC we're running on. The result
+/// is cached, as the SoC can't change while the process is running.
+fn soc_mesa_driver() -> Option<&'static str> {
+    static SOC_MESA_DRIVER: OnceLock<Option<&str>> = OnceLock::new();
+
+    *SOC_MESA_DRIVER
+        .get_or_init(|| device_tree_mesa_driver(Path::new("/proc/device-tree/compatible")))
+}
+
+/// Looks up the Mesa driver override for the device tree compatible ids in
+/// `path`. This is only a best-effort guess, so a device tree that can't be
+/// read means no override rather than an error.
+fn device_tree_mesa_driver(path: &Path) -> Option<&'static str> {
+    match fs::read_to_string(path) {
+        Ok(compatible) => mesa_driver_for_compatible(&compatible),
+        // Not a device tree platform.
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => {
+            debug!(path:?, err:%; "failed to read device tree compatible ids");
+            None
+        },
+    }
+}
+
+fn mesa_driver_for_compatible(compatible: &str) -> Option<&'static str> {
+    compatible.split('\0').find_map(|compat_id| {
+        SOC_MESA_DRIVERS
+            .iter()
+            .find(|&&(id, _)| id == compat_id)
+            .map(|&(_, driver)| driver)
+    })
+}
+
+/// Returns the directories where the krun programs may be installed next to
+/// the current executable, in order: its directory as given, as resolved, and
+/// the directory of `argv[0]` if it's a path. The current executable may have
+/// been run through a symlink from elsewhere, e.g. into the Nix store, where
+/// the other programs are not next to it.
+fn exec_dirs(current_exe: &Path, argv0: Option<&OsStr>) -> Vec<PathBuf> {
+    let resolved = current_exe.canonicalize().ok();
+    let argv0 = argv0
+        .filter(|argv0| argv0.as_bytes().contains(&b'/'))
+        .map(Path::new);
+
+    let mut dirs: Vec<PathBuf> = vec![];
+    for exe in [Some(current_exe), resolved.as_deref(), argv0]
+        .into_iter()
+        .flatten()
+    {
+        if let Some(dir) = exe.parent() {
+            if !dirs.iter().any(|d| d == dir) {
+                dirs.push(dir.to_owned());
+            }
+        }
+    }
+    dirs
+}
+
+fn find_in_dirs(program: &Path, dirs: &[PathBuf]) -> Result<Option<PathBuf>> {
+    for dir in dirs {
+        let path = dir.join(program);
+        if let Some(path) = find_executable(&path)
+            .with_context(|| format!("Failed to check existence of {path:?}"))?
+        {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
 }
 
 pub fn find_krun_exec<P>(program: P) -> Result<CString>
@@ -88,14 +815,28 @@ where
     P: AsRef<Path>,
 {
     let program = program.as_ref();
-    let path = find_in_path(program)
-        .with_context(|| format!("Failed to check existence of {program:?}"))?;
-    let path = if let Some(path) = path {
-        path
+    // Allow overriding where the krun programs are, e.g. to run from a build
+    // tree where they are neither in `PATH` nor next to the current executable.
+    let path = if let Ok(exec_dir) = env::var("KRUN_EXEC_DIR") {
+        let path = Path::new(&exec_dir).join(program);
+        find_executable(&path)
+            .with_context(|| format!("Failed to check existence of {path:?}"))?
+            .with_context(|| {
+                format!("{path:?} from `KRUN_EXEC_DIR` does not exist or is not executable")
+            })?
     } else {
-        let path = env::current_exe().and_then(|p| p.canonicalize());
-        let path = path.context("Failed to get path of current running executable")?;
-        path.with_file_name(program)
+        let path = find_in_path(program)
+            .with_context(|| format!("Failed to check existence of {program:?}"))?;
+        if let Some(path) = path {
+            path
+        } else {
+            let current_exe =
+                env::current_exe().context("Failed to get path of current running executable")?;
+            let exec_dirs = exec_dirs(&current_exe, env::args_os().next().as_deref());
+            find_in_dirs(program, &exec_dirs)?.with_context(|| {
+                format!("Failed to find {program:?} in `PATH` or next to the current executable")
+            })?
+        }
     };
     let path = CString::new(path.to_str().with_context(|| {
         format!("Failed to process {program:?} path as it contains invalid UTF-8")
@@ -104,3 +845,690 @@ where
 
     Ok(path)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::ffi::{OsStr, OsString};
+    use std::fs::Permissions;
+    use std::os::unix::ffi::OsStrExt as _;
+    use std::os::unix::fs::PermissionsExt as _;
+    use std::os::unix::net::UnixListener;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Tests that modify the process environment must hold this lock, so that
+    /// they don't run concurrently.
+    pub(crate) static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Puts back the env vars a test changes when dropped, even if it fails.
+    /// Create it after taking [`ENV_LOCK`], so that it's dropped first.
+    pub(crate) struct EnvGuard(Vec<(String, Option<OsString>)>);
+
+    impl EnvGuard {
+        pub(crate) fn new(keys: &[&str]) -> Self {
+            Self(
+                keys.iter()
+                    .map(|&key| (key.to_owned(), env::var_os(key)))
+                    .collect(),
+            )
+        }
+    }
+
+    impl Drop for EnvGuard {
+        fn drop(&mut self) {
+            for (key, value) in &self.0 {
+                match value {
+                    Some(value) => env::set_var(key, value),
+                    None => env::remove_var(key),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mesa_driver_from_compatible() {
+        assert_eq!(
+            mesa_driver_for_compatible("apple,j314s\0apple,t6000\0apple,arm-platform\0"),
+            Some("asahi")
+        );
+        assert_eq!(
+            mesa_driver_for_compatible("raspberrypi,4-model-b\0brcm,bcm2711\0"),
+            None
+        );
+    }
+
+    #[test]
+    fn mesa_driver_from_unreadable_device_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(device_tree_mesa_driver(dir.path()), None);
+        assert_eq!(
+            device_tree_mesa_driver(&dir.path().join("compatible")),
+            None
+        );
+
+        let compatible = dir.path().join("compatible");
+        fs::write(&compatible, "apple,j314s\0apple,arm-platform\0").unwrap();
+        assert_eq!(device_tree_mesa_driver(&compatible), Some("asahi"));
+    }
+
+    #[test]
+    fn list_well_known_env_vars() {
+        assert!(well_known_env_vars().contains(&"PATH"));
+        assert!(well_known_env_vars().contains(&"MESA_LOADER_DRIVER_OVERRIDE"));
+        assert!(!well_known_env_vars().contains(&"WAYLAND_DISPLAY"));
+    }
+
+    #[test]
+    fn forward_wayland_display() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["XDG_RUNTIME_DIR", "WAYLAND_DISPLAY", "DISPLAY"]);
+        env::set_var("XDG_RUNTIME_DIR", "/run/user/1000");
+        env::set_var("WAYLAND_DISPLAY", "wayland-1");
+        env::set_var("DISPLAY", ":0");
+
+        let env_map = prepare_env_vars(vec![]).unwrap();
+        assert_eq!(
+            env_map.get("HOST_WAYLAND_DISPLAY").map(String::as_str),
+            Some("/run/user/1000/wayland-1")
+        );
+        assert_eq!(env_map.get("HOST_DISPLAY").map(String::as_str), Some(":0"));
+
+        env::remove_var("WAYLAND_DISPLAY");
+        let env_map = prepare_env_vars(vec![]).unwrap();
+        assert!(!env_map.contains_key("HOST_WAYLAND_DISPLAY"));
+    }
+
+    #[test]
+    fn forward_x11_display_directly() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["DISPLAY", "KRUN_X11_PASSTHROUGH"]);
+        env::set_var("DISPLAY", ":0");
+
+        let env_map = prepare_env_vars(vec![]).unwrap();
+        assert_eq!(env_map.get("HOST_DISPLAY").map(String::as_str), Some(":0"));
+        assert!(!env_map.contains_key("DISPLAY"));
+
+        env::set_var("KRUN_X11_PASSTHROUGH", "direct");
+        let env_map = prepare_env_vars(vec![]).unwrap();
+        assert_eq!(env_map.get("DISPLAY").map(String::as_str), Some(":0"));
+        assert!(!env_map.contains_key("HOST_DISPLAY"));
+
+        env::set_var("KRUN_X11_PASSTHROUGH", "proxy");
+        let err = prepare_env_vars(vec![]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Invalid `KRUN_X11_PASSTHROUGH` value "proxy", the only mode is `direct`"#
+        );
+    }
+
+    #[test]
+    fn merge_path_like_env_vars() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let host_path = env::var("PATH").unwrap();
+        let resolve_path = |key: &str, value: &str| {
+            let env_map =
+                resolve_vm_env(vec![(key.to_owned(), EnvValue::Set(value.to_owned()))]).unwrap();
+            assert!(!env_map.contains_key("PATH+"));
+            env_map["PATH"].clone()
+        };
+
+        assert_eq!(
+            resolve_path("PATH", "+/opt/bin"),
+            format!("/opt/bin:{host_path}")
+        );
+        assert_eq!(
+            resolve_path("PATH+", "/opt/bin"),
+            format!("{host_path}:/opt/bin")
+        );
+        assert_eq!(resolve_path("PATH", "/opt/bin"), "/opt/bin");
+
+        // Nothing to merge with.
+        let env_map = resolve_vm_env(vec![(
+            "PKG_CONFIG_PATH".to_owned(),
+            EnvValue::Set("+/opt/lib/pkgconfig".to_owned()),
+        )])
+        .unwrap();
+        assert_eq!(env_map["PKG_CONFIG_PATH"], "/opt/lib/pkgconfig");
+        // Only path-like variables are merged.
+        let env_map = resolve_vm_env(vec![
+            ("KRUN_TEST".to_owned(), EnvValue::Set("+1".to_owned())),
+            ("KRUN_TEST+".to_owned(), EnvValue::Set("2".to_owned())),
+        ])
+        .unwrap();
+        assert_eq!(env_map["KRUN_TEST"], "+1");
+        assert_eq!(env_map["KRUN_TEST+"], "2");
+    }
+
+    #[test]
+    fn forward_ssh_agent_socket() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["SSH_AUTH_SOCK", "KRUN_TEST_NOT_SOCKET"]);
+        let dir = tempfile::tempdir().unwrap();
+        let agent_path = dir.path().join("agent.sock");
+        let _agent = UnixListener::bind(&agent_path).unwrap();
+        env::set_var("SSH_AUTH_SOCK", &agent_path);
+        env::set_var("KRUN_TEST_NOT_SOCKET", dir.path());
+
+        let sockets = resolve_forwarded_sockets(&["SSH_AUTH_SOCK".to_owned()]);
+        let err = resolve_forwarded_sockets(&[
+            "SSH_AUTH_SOCK".to_owned(),
+            "KRUN_TEST_NOT_SOCKET".to_owned(),
+        ])
+        .unwrap_err();
+        let sockets = sockets.unwrap();
+        assert_eq!(
+            sockets,
+            [ForwardedSocket {
+                env_var: "SSH_AUTH_SOCK".to_owned(),
+                host_path: agent_path,
+                port: FORWARDED_SOCKET_BASE_PORT,
+            }]
+        );
+        assert_eq!(
+            forwarded_sockets_env(&sockets),
+            (
+                FORWARDED_SOCKETS_ENV_VAR.to_owned(),
+                "SSH_AUTH_SOCK:7000".to_owned()
+            )
+        );
+        assert!(err.to_string().contains("is not a socket"));
+
+        env::remove_var("SSH_AUTH_SOCK");
+        let err = resolve_forwarded_sockets(&["SSH_AUTH_SOCK".to_owned()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`SSH_AUTH_SOCK` is not set, no socket to forward"
+        );
+    }
+
+    #[test]
+    fn forward_ssh_agent_automatically() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["SSH_AUTH_SOCK", "KRUN_NO_FORWARD", "KRUN_NO_SSH_AGENT"]);
+        let dir = tempfile::tempdir().unwrap();
+        let agent_path = dir.path().join("agent.sock");
+        let _agent = UnixListener::bind(&agent_path).unwrap();
+
+        env::set_var("SSH_AUTH_SOCK", &agent_path);
+        let sockets = resolve_forwarded_sockets(&[]);
+        env::set_var("KRUN_NO_FORWARD", "1");
+        let no_forward_sockets = resolve_forwarded_sockets(&[]);
+        env::remove_var("KRUN_NO_FORWARD");
+        env::set_var("KRUN_NO_SSH_AGENT", "1");
+        let no_agent_sockets = resolve_forwarded_sockets(&[]);
+        let requested_sockets = resolve_forwarded_sockets(&["SSH_AUTH_SOCK".to_owned()]);
+        env::remove_var("KRUN_NO_SSH_AGENT");
+        // An agent that's gone is skipped, not an error.
+        env::set_var("SSH_AUTH_SOCK", dir.path().join("gone.sock"));
+        let gone_sockets = resolve_forwarded_sockets(&[]);
+        env::remove_var("SSH_AUTH_SOCK");
+        let sockets = sockets.unwrap();
+        assert_eq!(
+            sockets,
+            [ForwardedSocket {
+                env_var: "SSH_AUTH_SOCK".to_owned(),
+                host_path: agent_path,
+                port: FORWARDED_SOCKET_BASE_PORT,
+            }]
+        );
+        let (key, value) = forwarded_sockets_env(&sockets);
+        assert_eq!(key, FORWARDED_SOCKETS_ENV_VAR);
+        assert_eq!(value, "SSH_AUTH_SOCK:7000");
+        assert!(no_forward_sockets.unwrap().is_empty());
+        assert!(no_agent_sockets.unwrap().is_empty());
+        // Still forwarded when asked for explicitly.
+        assert_eq!(requested_sockets.unwrap(), sockets);
+        assert!(gone_sockets.unwrap().is_empty());
+        assert!(resolve_forwarded_sockets(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn skip_automatic_forwarding() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["DISPLAY", "WAYLAND_DISPLAY", "KRUN_NO_FORWARD"]);
+        env::set_var("DISPLAY", ":0");
+        env::set_var("WAYLAND_DISPLAY", "wayland-1");
+        env::set_var("KRUN_NO_FORWARD", "1");
+
+        let env_map = prepare_env_vars(vec![(
+            "KRUN_TEST_SET".to_owned(),
+            EnvValue::Set("set".to_owned()),
+        )])
+        .unwrap();
+        let mut keys: Vec<_> = env_map.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, [INSIDE_VM_ENV_VAR, "KRUN_TEST_SET"]);
+    }
+
+    #[test]
+    fn rewrite_env_var_values() {
+        let rules = parse_env_rewrites(
+            "HOME:/host/home/user=>/home/user, XDG_DATA_HOME:/host/home/*=>/home/",
+        )
+        .unwrap();
+        assert_eq!(
+            rules[1],
+            EnvRewrite {
+                key: "XDG_DATA_HOME".to_owned(),
+                from: "/host/home/".to_owned(),
+                to: "/home/".to_owned(),
+                prefix: true,
+            }
+        );
+
+        let mut env_map = HashMap::from([
+            ("HOME".to_owned(), "/host/home/user".to_owned()),
+            (
+                "XDG_DATA_HOME".to_owned(),
+                "/host/home/user/.local/share".to_owned(),
+            ),
+        ]);
+        rewrite_env_vars(&mut env_map, &rules);
+        assert_eq!(env_map["HOME"], "/home/user");
+        assert_eq!(env_map["XDG_DATA_HOME"], "/home/user/.local/share");
+
+        // An exact rule doesn't rewrite longer values, and a prefix rule
+        // doesn't rewrite values that don't start with its prefix.
+        let mut env_map = HashMap::from([
+            ("HOME".to_owned(), "/host/home/user2".to_owned()),
+            ("XDG_DATA_HOME".to_owned(), "/data".to_owned()),
+        ]);
+        let expected = env_map.clone();
+        rewrite_env_vars(&mut env_map, &rules);
+        assert_eq!(env_map, expected);
+
+        for rule in ["HOME", "HOME:/a", ":/a=>/b", "HOME:=>/b"] {
+            assert!(parse_env_rewrites(rule).is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn forward_host_locale() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&[&LOCALE_ENV_VARS[..], &["LC_ALL"]].concat());
+        for key in LOCALE_ENV_VARS {
+            env::remove_var(key);
+        }
+        env::remove_var("LC_ALL");
+        env::set_var("LANG", "fr_FR.UTF-8");
+        env::set_var("LC_TIME", "en_GB.UTF-8");
+        env::set_var("LC_PAPER", "");
+
+        let env_map = prepare_env_vars(vec![]).unwrap();
+        assert_eq!(env_map.get("LANG").map(String::as_str), Some("fr_FR.UTF-8"));
+        assert_eq!(
+            env_map.get("LC_TIME").map(String::as_str),
+            Some("en_GB.UTF-8")
+        );
+        assert!(!env_map.contains_key("LC_PAPER"));
+        assert!(!env_map.contains_key("LC_ALL"));
+
+        env::set_var("LC_ALL", "de_DE.UTF-8");
+        let env_map = prepare_env_vars(vec![]).unwrap();
+        assert_eq!(
+            env_map.get("LC_ALL").map(String::as_str),
+            Some("de_DE.UTF-8")
+        );
+        assert!(!env_map.contains_key("LANG"));
+        assert!(!env_map.contains_key("LC_TIME"));
+    }
+
+    #[test]
+    fn forward_host_timezone() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["TZ"]);
+        let dir = tempfile::tempdir().unwrap();
+        let localtime = dir.path().join("localtime");
+
+        env::set_var("TZ", "Europe/Paris");
+        let tz = host_timezone(&localtime);
+        env::remove_var("TZ");
+        assert_eq!(tz.unwrap().as_deref(), Some("Europe/Paris"));
+
+        // No TZ and no localtime.
+        assert_eq!(host_timezone(&localtime).unwrap(), None);
+
+        std::os::unix::fs::symlink("../usr/share/zoneinfo/America/New_York", &localtime).unwrap();
+        assert_eq!(
+            host_timezone(&localtime).unwrap().as_deref(),
+            Some("America/New_York")
+        );
+
+        fs::remove_file(&localtime).unwrap();
+        fs::write(&localtime, "TZif").unwrap();
+        assert_eq!(host_timezone(&localtime).unwrap(), None);
+    }
+
+    #[test]
+    fn skip_missing_xauthority() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["HOME", "DISPLAY", "XAUTHORITY"]);
+        let home = tempfile::tempdir().unwrap();
+        let home_xauthority = home.path().join(".Xauthority");
+        env::set_var("HOME", home.path());
+        env::set_var("DISPLAY", ":0");
+        env::set_var("XAUTHORITY", "/nonexistent/krun-test-xauthority");
+
+        let env_map = prepare_env_vars(vec![]).unwrap();
+        assert!(!env_map.contains_key("XAUTHORITY"));
+        assert_eq!(env_map.get("HOST_DISPLAY").map(String::as_str), Some(":0"));
+
+        fs::write(&home_xauthority, b"").unwrap();
+        let env_map = prepare_env_vars(vec![]).unwrap();
+        assert_eq!(
+            env_map.get("XAUTHORITY").map(PathBuf::from),
+            Some(home_xauthority.clone())
+        );
+
+        env::set_var("XAUTHORITY", &home_xauthority);
+        let env_map = prepare_env_vars(vec![]).unwrap();
+        assert_eq!(
+            env_map.get("XAUTHORITY").map(PathBuf::from),
+            Some(home_xauthority)
+        );
+    }
+
+    #[test]
+    fn resolve_vm_env_without_side_effects() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        env::set_var("KRUN_TEST_INHERITED", "inherited");
+        env::remove_var("KRUN_TEST_UNSET");
+        let host_env: HashMap<_, _> = env::vars_os().collect();
+
+        let env_map = resolve_vm_env(vec![
+            ("KRUN_TEST_SET".to_owned(), EnvValue::Set("set".to_owned())),
+            ("KRUN_TEST_INHERITED".to_owned(), EnvValue::Inherit),
+            ("KRUN_TEST_UNSET".to_owned(), EnvValue::InheritIfSet),
+        ])
+        .unwrap();
+        assert_eq!(env::vars_os().collect::<HashMap<_, _>>(), host_env);
+        env::remove_var("KRUN_TEST_INHERITED");
+        assert_eq!(
+            env_map.get("KRUN_TEST_SET").map(String::as_str),
+            Some("set")
+        );
+        assert_eq!(
+            env_map.get("KRUN_TEST_INHERITED").map(String::as_str),
+            Some("inherited")
+        );
+        assert!(!env_map.contains_key("KRUN_TEST_UNSET"));
+        assert_eq!(
+            env_map.get(INSIDE_VM_ENV_VAR).map(String::as_str),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn normalize_forwarded_path() {
+        let exists = |dir: &Path| dir != Path::new("/home/user/.host-only/bin");
+        assert_eq!(
+            normalize_path(None, "/usr/bin::/bin:/usr/bin:/bin:", exists),
+            "/usr/bin:/bin"
+        );
+        assert_eq!(
+            normalize_path(
+                Some("/opt/guest/bin:"),
+                "/home/user/.host-only/bin:/usr/bin:/opt/guest/bin",
+                exists
+            ),
+            "/opt/guest/bin:/usr/bin"
+        );
+
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["PATH", "KRUN_NORMALIZE_PATH"]);
+        env::set_var("PATH", "/:/krun-test-missing::/");
+        env::remove_var("KRUN_NORMALIZE_PATH");
+        let verbatim = prepare_env_vars(vec![]).unwrap();
+        env::set_var("KRUN_NORMALIZE_PATH", "1");
+        let normalized = prepare_env_vars(vec![]).unwrap();
+        assert_eq!(verbatim["PATH"], "/:/krun-test-missing::/");
+        assert_eq!(normalized["PATH"], "/");
+    }
+
+    #[test]
+    fn reject_non_utf8_well_known_env_var() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        env::set_var("LIBGL_DRIVERS_PATH", OsStr::from_bytes(b"/usr/lib/\xff"));
+        let err = prepare_env_vars(vec![]).unwrap_err();
+        env::remove_var("LIBGL_DRIVERS_PATH");
+        assert_eq!(
+            err.to_string(),
+            "Failed to get `LIBGL_DRIVERS_PATH` env var: its value is not valid UTF-8"
+        );
+    }
+
+    #[test]
+    fn forward_passthrough_env_vars() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["KRUN_TEST_WINEPREFIX", "KRUN_TEST_UNSET"]);
+        env::set_var("KRUN_TEST_WINEPREFIX", "/home/user/.wine");
+        env::remove_var("KRUN_TEST_UNSET");
+        let passthrough_env_vars =
+            parse_passthrough_env_vars("KRUN_TEST_WINEPREFIX, KRUN_TEST_UNSET,");
+        assert_eq!(
+            passthrough_env_vars,
+            ["KRUN_TEST_WINEPREFIX", "KRUN_TEST_UNSET"]
+        );
+
+        let mut env_map = HashMap::new();
+        forward_host_env_vars(&mut env_map, &passthrough_env_vars).unwrap();
+        assert_eq!(
+            env_map.get("KRUN_TEST_WINEPREFIX").map(String::as_str),
+            Some("/home/user/.wine")
+        );
+        assert!(!env_map.contains_key("KRUN_TEST_UNSET"));
+    }
+
+    #[test]
+    fn forward_user_env_vars() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        env::set_var("KRUN_TEST_INHERITED", "inherited");
+        env::remove_var("KRUN_TEST_MISSING");
+
+        let env_map = prepare_env_vars(vec![
+            ("KRUN_TEST_SET".to_owned(), EnvValue::Set("".to_owned())),
+            ("KRUN_TEST_INHERITED".to_owned(), EnvValue::Inherit),
+            ("KRUN_TEST_MISSING".to_owned(), EnvValue::InheritIfSet),
+        ])
+        .unwrap();
+        assert_eq!(env_map.get("KRUN_TEST_SET").map(String::as_str), Some(""));
+        assert_eq!(
+            env_map.get("KRUN_TEST_INHERITED").map(String::as_str),
+            Some("inherited")
+        );
+        assert!(!env_map.contains_key("KRUN_TEST_MISSING"));
+        assert_eq!(
+            env_map.get(INSIDE_VM_ENV_VAR).map(String::as_str),
+            Some("1")
+        );
+
+        let env_map = prepare_env_vars(vec![(
+            "KRUN_TEST_INHERITED".to_owned(),
+            EnvValue::InheritIfSet,
+        )])
+        .unwrap();
+        assert_eq!(
+            env_map.get("KRUN_TEST_INHERITED").map(String::as_str),
+            Some("inherited")
+        );
+
+        let err = prepare_env_vars(vec![("KRUN_TEST_MISSING".to_owned(), EnvValue::Inherit)])
+            .unwrap_err();
+        env::remove_var("KRUN_TEST_INHERITED");
+        assert_eq!(err.to_string(), "Failed to get `KRUN_TEST_MISSING` env var");
+    }
+
+    #[test]
+    fn validate_env_var_names_and_size() {
+        let env_map = HashMap::from([("FOO".to_owned(), "bar".to_owned())]);
+        validate_env_vars(&env_map, 8).unwrap();
+        assert!(validate_env_vars(&env_map, 7)
+            .unwrap_err()
+            .to_string()
+            .contains("more than the maximum of 7 bytes"));
+
+        let env_map = HashMap::from([("FOO=BAR".to_owned(), "baz".to_owned())]);
+        assert_eq!(
+            validate_env_vars(&env_map, ENV_MAX_SIZE)
+                .unwrap_err()
+                .to_string(),
+            r#"Invalid env var name "FOO=BAR""#
+        );
+
+        let env_map = HashMap::from([("FOO".to_owned(), "b\0ar".to_owned())]);
+        assert!(validate_env_vars(&env_map, ENV_MAX_SIZE).is_err());
+
+        let env_map = HashMap::from([("PATH".to_owned(), "x".repeat(ENV_MAX_SIZE))]);
+        assert!(validate_env_vars(&env_map, ENV_MAX_SIZE).is_err());
+    }
+
+    #[test]
+    fn parse_env_file_entries() {
+        let env = parse_env_file(
+            r#"
+# Comment
+FOO=bar
+  SPACED = value with spaces
+EMPTY=
+SINGLE='it''s $literal \n'
+DOUBLE="say \"hi\"\nback\\slash"
+FOO=override
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            env,
+            [
+                ("FOO", "bar"),
+                ("SPACED", "value with spaces"),
+                ("EMPTY", ""),
+                ("SINGLE", "it''s $literal \\n"),
+                ("DOUBLE", "say \"hi\"\nback\\slash"),
+                ("FOO", "override"),
+            ]
+            .map(|(key, value)| (key.to_owned(), EnvValue::Set(value.to_owned())))
+        );
+    }
+
+    #[test]
+    fn parse_malformed_env_file() {
+        let err = parse_env_file("FOO=bar\nBAR\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected KEY=VALUE");
+        assert!(parse_env_file("=bar").is_err());
+        assert!(parse_env_file("FOO BAR=baz").is_err());
+        assert!(parse_env_file("FOO='bar").is_err());
+        assert!(parse_env_file(r#"FOO="bar"baz"#).is_err());
+        assert!(parse_env_file(r#"FOO="bar\""#).is_err());
+    }
+
+    #[test]
+    fn expand_host_env_vars() {
+        let host_env = HashMap::from([
+            ("HOSTNAME".to_owned(), "fedora".to_owned()),
+            ("EMPTY".to_owned(), String::new()),
+        ]);
+        let expand = |value: &str| expand_vars(value, |key| Ok(host_env.get(key).cloned()));
+
+        assert_eq!(expand("--id=${HOSTNAME}").unwrap(), "--id=fedora");
+        assert_eq!(expand("${HOSTNAME}-${HOSTNAME}").unwrap(), "fedora-fedora");
+        assert_eq!(
+            expand("$$HOME costs $5 or $$${HOSTNAME}").unwrap(),
+            "$HOME costs $5 or $fedora"
+        );
+        assert_eq!(expand("${USER:-nobody}").unwrap(), "nobody");
+        assert_eq!(
+            expand("${EMPTY:-x}${HOSTNAME:-x}${USER:-}").unwrap(),
+            "xfedora"
+        );
+        assert_eq!(expand("${EMPTY}").unwrap(), "");
+        assert_eq!(expand("$").unwrap(), "$");
+
+        assert_eq!(
+            expand("--user=${USER}").unwrap_err().to_string(),
+            r#"Env var `USER` in "--user=${USER}" is not set"#
+        );
+        assert!(expand("${HOSTNAME").is_err());
+        assert!(expand("${}").is_err());
+        assert!(expand("${:-x}").is_err());
+    }
+
+    #[test]
+    fn redact_sensitive_env_vars() {
+        let env_map = HashMap::from([
+            ("FOO_TOKEN".to_owned(), "hunter2".to_owned()),
+            ("XAUTHORITY".to_owned(), "/run/user/1000/xauth".to_owned()),
+            ("PATH".to_owned(), "/usr/bin".to_owned()),
+        ]);
+        assert_eq!(
+            format!("{:?}", RedactedEnv(&env_map, &SENSITIVE_ENV_VARS)),
+            r#"{"FOO_TOKEN": "***", "PATH": "/usr/bin", "XAUTHORITY": "***"}"#
+        );
+        assert_eq!(
+            format!("{:?}", RedactedEnv(&env_map, &["PA*"])),
+            r#"{"FOO_TOKEN": "hunter2", "PATH": "***", "XAUTHORITY": "/run/user/1000/xauth"}"#
+        );
+    }
+
+    #[test]
+    fn match_env_var_patterns() {
+        assert!(matches_pattern("GITHUB_TOKEN", "*TOKEN*"));
+        assert!(matches_pattern("TOKEN", "*TOKEN*"));
+        assert!(!matches_pattern("TOKE", "*TOKEN*"));
+        assert!(matches_pattern("MY_SECRET", "*SECRET"));
+        assert!(!matches_pattern("SECRET_KEY", "*SECRET"));
+        assert!(matches_pattern("AWS_KEY", "AWS_*"));
+        assert!(matches_pattern("XAUTHORITY", "XAUTHORITY"));
+        assert!(!matches_pattern("XAUTHORITY2", "XAUTHORITY"));
+        assert!(matches_pattern("ANYTHING", "*"));
+    }
+
+    #[test]
+    fn find_krun_exec_next_to_symlinked_exe() {
+        let dir = tempfile::tempdir().unwrap();
+        let (bin, store) = (dir.path().join("bin"), dir.path().join("store"));
+        fs::create_dir(&bin).unwrap();
+        fs::create_dir(&store).unwrap();
+        fs::write(store.join("krun"), "#!/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink(store.join("krun"), bin.join("krun")).unwrap();
+        let guest = bin.join("krun-guest");
+        fs::write(&guest, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&guest, Permissions::from_mode(0o755)).unwrap();
+
+        // Run through the symlink in `bin`, which only `argv[0]` tells.
+        let argv0 = bin.join("krun");
+        let dirs = exec_dirs(&store.join("krun"), Some(argv0.as_os_str()));
+        assert_eq!(dirs, [store.clone(), bin.clone()]);
+        assert_eq!(
+            find_in_dirs(Path::new("krun-guest"), &dirs).unwrap(),
+            Some(guest.canonicalize().unwrap())
+        );
+
+        // Found as is, before being resolved.
+        let dirs = exec_dirs(&bin.join("krun"), Some(OsStr::new("krun")));
+        assert_eq!(dirs, [bin.clone(), store.clone()]);
+        assert_eq!(find_in_dirs(Path::new("krun-server"), &dirs).unwrap(), None);
+    }
+
+    #[test]
+    fn find_krun_exec_in_exec_dir() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("krun-guest");
+        fs::write(&path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o755)).unwrap();
+        env::set_var("KRUN_EXEC_DIR", dir.path());
+
+        let found = find_krun_exec("krun-guest");
+        let missing = find_krun_exec("krun-server");
+        env::remove_var("KRUN_EXEC_DIR");
+        assert_eq!(
+            found.unwrap().to_str().unwrap(),
+            path.canonicalize().unwrap().to_str().unwrap()
+        );
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .contains("does not exist or is not executable"));
+    }
+}
@@ -1,34 +1,401 @@
-use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::TcpStream;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, ErrorKind, IsTerminal as _, Read, Seek as _, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::os::fd::{AsFd as _, BorrowedFd, FromRawFd as _, OwnedFd, RawFd};
+use std::os::unix::fs::{FileTypeExt as _, OpenOptionsExt as _};
+use std::os::unix::thread::JoinHandleExt as _;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use std::{process, thread};
 
 use anyhow::{anyhow, Context, Result};
-use rustix::fs::{flock, FlockOperation};
+use log::{debug, trace};
+use nix::sys::pthread::pthread_kill;
+use nix::sys::signal::{SigSet, SigmaskHow, Signal};
+use rustix::fs::{flock, FlockOperation, Mode, OFlags};
+use rustix::io::{fcntl_getfd, FdFlags};
 use rustix::path::Arg;
-use utils::launch::Launch;
+use rustix::process::umask;
+use utils::env::runtime_dir;
+use utils::launch::{
+    parse_frame_header, read_frame, write_frame, BatchPolicy, BatchStatus, Capabilities,
+    ErrorResponse, FrameKind, Launch, LaunchInfo, Request, ServerInfo,
+    CAPABILITIES_PROTOCOL_VERSION, ERROR_RESPONSE_PREFIX, FRAME_HEADER_LEN, HEARTBEAT_INTERVAL,
+    MAX_REQUEST_LEN, MAX_STDIN_LEN,
+};
 
-use crate::env::prepare_env_vars;
+use crate::env::{
+    expand_env_vars, prepare_env_vars, read_env_file, redact_env_values, validate_env_var_name,
+    EnvValue, INSIDE_VM_ENV_VAR,
+};
 
+/// How long to wait for the krun server to accept a connection, unless
+/// overridden with `KRUN_CONNECT_TIMEOUT` (in seconds).
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many times to retry connecting to the server of another krun instance
+/// when that failed or timed out, e.g. because the server is still starting
+/// up, unless overridden with `KRUN_CONNECT_RETRIES`, which
+/// is capped at `MAX_CONNECT_RETRIES`.
+const CONNECT_RETRIES: u32 = 3;
+const MAX_CONNECT_RETRIES: u32 = 100;
+
+/// How many times to read a lock file held by another krun instance, waiting
+/// `LOCK_READ_INTERVAL` in between, before giving up on finding its port.
+const LOCK_READ_TRIES: u32 = 5;
+const LOCK_READ_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a launch waiting for the server to accept it checks whether it
+/// was cancelled, see [`LaunchOptions::cancel`].
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long to wait for the krun server to answer a launch request, which
+/// includes spawning the command.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Address of the krun server, unless overridden with `KRUN_SERVER_HOST`
+/// together with `KRUN_SERVER_PORT`.
+const DEFAULT_SERVER_HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// How long to wait when checking whether the server recorded in the lock
+/// file is still accepting connections.
+const LIVENESS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many times to check whether the server of a krun instance holding the
+/// lock is there, waiting `LIVENESS_RETRY_INTERVAL` and then twice as long
+/// each time in between, as it may still be starting up.
+const LIVENESS_TRIES: u32 = 5;
+const LIVENESS_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
 pub enum LaunchResult {
-    LaunchRequested,
+    LaunchRequested {
+        /// Exit code of the command launched by the krun server.
+        exit_code: i32,
+    },
     LockAcquired {
         lock_file: File,
+        reason: LockReason,
         command: PathBuf,
         command_args: Vec<String>,
-        env: Vec<(String, Option<String>)>,
+        env: Vec<(String, EnvValue)>,
     },
+    /// The launch request was printed instead of being sent.
+    DryRun,
+    /// The krun server launched the command detached from krun, see
+    /// [`LaunchOptions::detach`].
+    Detached {
+        /// Id of the launch, as in [`list_launches`] and [`kill_launch`].
+        id: u64,
+    },
+}
+
+/// Why [`LaunchResult::LockAcquired`] made us the krun instance to start the
+/// microVM.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LockReason {
+    /// There was no lock file yet.
+    FirstStart,
+    /// The lock file was left behind by a krun instance that is gone, whether
+    /// it exited or crashed.
+    TookOverStale,
+}
+
+/// Port of the krun server, unless configured otherwise.
+pub const DEFAULT_SERVER_PORT: u32 = 3334;
+
+/// Builds the arguments of [`launch_or_lock`], validating them before the
+/// launch.
+///
+/// ```
+/// use krun::launch::LaunchBuilder;
+///
+/// let err = LaunchBuilder::new().arg("-l").launch().unwrap_err();
+/// assert_eq!(err.to_string(), "No command to launch");
+///
+/// let err = LaunchBuilder::new()
+///     .command("ls")
+///     .env("FOO=BAR", "baz")
+///     .launch()
+///     .unwrap_err();
+/// assert_eq!(err.to_string(), r#"Invalid env var name "FOO=BAR""#);
+/// ```
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use krun::env::EnvValue;
+/// use krun::launch::{LaunchBuilder, LaunchResult};
+///
+/// let result = LaunchBuilder::new()
+///     .command("ls")
+///     .args(["-l", "-a"])
+///     .env("LC_ALL", "C")
+///     .env_value("WINEPREFIX", EnvValue::InheritIfSet)
+///     .cwd("/tmp")
+///     .timeout(Duration::from_secs(30))
+///     .launch()?;
+/// if let LaunchResult::LaunchRequested { exit_code } = result {
+///     println!("ls exited with {exit_code}");
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct LaunchBuilder {
+    server_port: u32,
+    command: Option<PathBuf>,
+    command_args: Vec<String>,
+    env: Vec<(String, EnvValue)>,
+    env_file: Option<PathBuf>,
+    options: LaunchOptions,
+}
+
+impl LaunchBuilder {
+    pub fn new() -> Self {
+        Self {
+            server_port: DEFAULT_SERVER_PORT,
+            command: None,
+            command_args: Vec::new(),
+            env: Vec::new(),
+            env_file: None,
+            options: LaunchOptions::default(),
+        }
+    }
+
+    /// Port of the server of the microVM, if it needs to be started.
+    pub fn server_port(mut self, server_port: u32) -> Self {
+        self.server_port = server_port;
+        self
+    }
+
+    pub fn command(mut self, command: impl Into<PathBuf>) -> Self {
+        self.command = Some(command.into());
+        self
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.command_args.push(arg.into());
+        self
+    }
+
+    pub fn args<I>(mut self, args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.command_args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets an env var for the command.
+    pub fn env(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env_value(key, EnvValue::Set(value.into()))
+    }
+
+    /// Sets or inherits an env var for the command, as with `--env`.
+    pub fn env_value(mut self, key: impl Into<String>, value: EnvValue) -> Self {
+        self.env.push((key.into(), value));
+        self
+    }
+
+    /// Reads env vars for the command from a file, as with `--env-file`. Env
+    /// vars set on the builder take precedence.
+    pub fn env_file(mut self, env_file: impl Into<PathBuf>) -> Self {
+        self.env_file = Some(env_file.into());
+        self
+    }
+
+    /// Working directory of the command, instead of the current one.
+    pub fn cwd(mut self, cwd: impl Into<PathBuf>) -> Self {
+        self.options.cwd = Some(cwd.into());
+        self
+    }
+
+    /// Kills the command if it runs for longer than `timeout`, as with
+    /// `--timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Runs the command as `user`, a user name or uid in the guest, as with
+    /// `--user`.
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.options.user = Some(user.into());
+        self
+    }
+
+    /// Makes the command see `argv0` as `argv[0]`, as with `--argv0`.
+    pub fn argv0(mut self, argv0: impl Into<String>) -> Self {
+        self.options.argv0 = Some(argv0.into());
+        self
+    }
+
+    /// Returns as soon as the command is launched, as with `--detach`.
+    pub fn detach(mut self, detach: bool) -> Self {
+        self.options.detach = detach;
+        self
+    }
+
+    /// Fails rather than waits for the server to have a free slot for the
+    /// command, as with `--fail-if-busy`.
+    pub fn fail_if_busy(mut self, fail_if_busy: bool) -> Self {
+        self.options.fail_if_busy = fail_if_busy;
+        self
+    }
+
+    /// Starts the command from a clean environment, as with `--clean-env`.
+    pub fn clean_env(mut self, clean_env: bool) -> Self {
+        self.options.clean_env = clean_env;
+        self
+    }
+
+    /// Expands host env vars in the arguments of the command, as with
+    /// `--expand-args`.
+    pub fn expand_args(mut self, expand_args: bool) -> Self {
+        self.options.expand_args = expand_args;
+        self
+    }
+
+    /// Fails rather than warns about a command path that doesn't exist, as
+    /// with `--strict`.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        self
+    }
+
+    /// Writes `stdin` to the stdin of the command, see [`LaunchOptions::stdin`].
+    pub fn stdin(mut self, stdin: impl Into<Vec<u8>>) -> Self {
+        self.options.stdin = stdin.into();
+        self
+    }
+
+    /// Takes Linux capabilities away from the command, as with `--drop-caps`
+    /// or `--keep-caps`.
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.options.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Leaves the command with the umask of the server, as with
+    /// `--server-umask`.
+    pub fn server_umask(mut self, server_umask: bool) -> Self {
+        self.options.server_umask = server_umask;
+        self
+    }
+
+    /// Writes the stdout of the command to `path` in the guest, as with
+    /// `--stdout`.
+    pub fn stdout_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.stdout_path = Some(path.into());
+        self
+    }
+
+    /// Writes the stderr of the command to `path` in the guest, as with
+    /// `--stderr`.
+    pub fn stderr_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.stderr_path = Some(path.into());
+        self
+    }
+
+    /// Appends to the output files rather than truncating them, as with
+    /// `--append-output`.
+    pub fn append_output(mut self, append_output: bool) -> Self {
+        self.options.append_output = append_output;
+        self
+    }
+
+    /// Sends the stderr of the command wherever its stdout goes, as with
+    /// `--merge-stderr`.
+    pub fn merge_stderr(mut self, merge_stderr: bool) -> Self {
+        self.options.merge_stderr = merge_stderr;
+        self
+    }
+
+    /// Gives up on the launch once `cancel` is set, see [`LaunchOptions::cancel`].
+    pub fn cancel_token(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.options.cancel = Some(cancel);
+        self
+    }
+
+    /// Sends the SIGINT and SIGTERM we get to the command, see
+    /// [`LaunchOptions::forward_signals`].
+    pub fn forward_signals(mut self, forward_signals: bool) -> Self {
+        self.options.forward_signals = forward_signals;
+        self
+    }
+
+    /// Prints the launch request instead of sending it, as with `--dry-run`.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    pub fn launch(self) -> Result<LaunchResult> {
+        let command = self
+            .command
+            .filter(|command| !command.as_os_str().is_empty())
+            .context("No command to launch")?;
+        for (key, _) in &self.env {
+            validate_env_var_name(key)?;
+        }
+        if let Some(cwd) = &self.options.cwd {
+            if !cwd.is_absolute() {
+                return Err(anyhow!("Working directory {cwd:?} is not an absolute path"));
+            }
+        }
+        if self.options.timeout == Some(Duration::ZERO) {
+            return Err(anyhow!("Timeout must be greater than 0"));
+        }
+        if self.options.user.as_deref() == Some("") {
+            return Err(anyhow!("User must not be empty"));
+        }
+
+        launch_or_lock(
+            self.server_port,
+            command,
+            self.command_args,
+            self.env,
+            self.env_file.as_deref(),
+            self.options,
+        )
+    }
 }
 
+impl Default for LaunchBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Errors requesting a launch from the krun server. They are the source of the
+/// errors returned by [`launch_or_lock`], so they can be recovered with
+/// [`anyhow::Error::downcast_ref`].
 #[derive(Debug)]
-enum LaunchError {
+pub enum LaunchError {
+    /// The server could not be reached.
     Connection(std::io::Error),
     Json(serde_json::Error),
+    /// The server rejected the launch request, or the connection broke after
+    /// the command was launched.
     Server(String),
+    /// The server did not accept the connection in time.
+    Timeout(Duration),
+    /// The server accepted the connection, but did not answer the launch
+    /// request in time.
+    ResponseTimeout(Duration),
+    /// The launch was given up on through [`LaunchOptions::cancel`].
+    Cancelled,
+    /// Something failed on our side, e.g. setting up signal forwarding.
+    Io(io::Error),
+    /// The command was given up on after a second signal, see
+    /// [`LaunchOptions::forward_signals`].
+    Interrupted(Signal),
 }
 
 impl Error for LaunchError {}
@@ -45,57 +412,275 @@ impl Display for LaunchError {
             Self::Server(ref err) => {
                 write!(f, "krun server returned an error: {err}")
             },
+            Self::Timeout(ref timeout) => {
+                write!(f, "timed out connecting to krun server after {timeout:?}")
+            },
+            Self::ResponseTimeout(ref timeout) => {
+                write!(
+                    f,
+                    "timed out waiting for krun server to respond after {timeout:?}"
+                )
+            },
+            Self::Cancelled => write!(f, "launch was cancelled"),
+            Self::Io(ref err) => write!(f, "{err}"),
+            Self::Interrupted(signal) => {
+                write!(f, "gave up on the launched command after {signal}")
+            },
+        }
+    }
+}
+
+impl LaunchError {
+    /// Kind of error, as reported by [`launch_error_json`].
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Connection(_) => "connection",
+            Self::Json(_) => "json",
+            Self::Server(_) => "server",
+            Self::Timeout(_) | Self::ResponseTimeout(_) => "timeout",
+            Self::Cancelled => "cancelled",
+            Self::Io(_) => "io",
+            Self::Interrupted(_) => "interrupted",
         }
     }
 }
 
+/// Context of the errors returned by [`launch_or_lock`] when requesting the
+/// launch from the server failed.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct LaunchFailure {
+    /// Port of the server the launch was requested to.
+    pub port: u32,
+    /// How many times connecting to the server was retried.
+    pub retries: u32,
+}
+
+impl Display for LaunchFailure {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "could not request launch to server")
+    }
+}
+
+/// Describes an error returned by [`launch_or_lock`] as a JSON object, for
+/// tools that run krun with `KRUN_OUTPUT=json`: `error` is the full error
+/// message, `kind` the [`LaunchError::kind`] (`null` for errors that happened
+/// before contacting the server), and `port` and `retries` come from the
+/// [`LaunchFailure`] if any.
+pub fn launch_error_json(err: &anyhow::Error) -> serde_json::Value {
+    let failure = err.downcast_ref::<LaunchFailure>();
+    serde_json::json!({
+        "error": format!("{err:#}"),
+        "kind": err.downcast_ref::<LaunchError>().map(LaunchError::kind),
+        "port": failure.map(|failure| failure.port),
+        "retries": failure.map(|failure| failure.retries),
+    })
+}
+
+/// Options of [`launch_or_lock`] for commands launched by a running krun
+/// instance.
+#[derive(Clone, Debug, Default)]
+pub struct LaunchOptions {
+    /// Working directory of the command, instead of the current one.
+    pub cwd: Option<PathBuf>,
+    /// Kill the command if it runs for longer than this. It then exits with
+    /// [`TIMED_OUT_EXIT_CODE`].
+    pub timeout: Option<Duration>,
+    /// Have the server kill the command if we send nothing for this long,
+    /// which should be several [`HEARTBEAT_INTERVAL`]s, see
+    /// [`Launch::idle_timeout_ms`].
+    pub idle_timeout: Option<Duration>,
+    /// Name or uid of the user to run the command as in the guest.
+    pub user: Option<String>,
+    /// Print the launch request instead of sending it.
+    pub dry_run: bool,
+    /// Return as soon as the command is launched, leaving it running in the
+    /// microVM with its output logged to a file there.
+    pub detach: bool,
+    /// What the command sees as `argv[0]`, instead of the base name of the
+    /// command.
+    pub argv0: Option<String>,
+    /// Fail rather than wait if the server already runs as many commands as
+    /// it allows.
+    pub fail_if_busy: bool,
+    /// Start the command from a clean environment in the guest, with only the
+    /// env vars passed to it and a safe baseline of `PATH`, `HOME` and `TERM`.
+    pub clean_env: bool,
+    /// Expand `${VAR}` in the arguments of the command to the value of the
+    /// host env var `VAR`, as [`expand_env_vars`] does.
+    pub expand_args: bool,
+    /// Fail rather than warn when the command is a path that doesn't exist on
+    /// the host, and so most likely not in the microVM either.
+    pub strict: bool,
+    /// Checked before each attempt to reach the server, and watched until the
+    /// server accepts the launch, including while it's queued. Once set, the
+    /// launch fails with [`LaunchError::Cancelled`]. A connect attempt under
+    /// way is not interrupted, but the connect timeout bounds it.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Written to the stdin of the command, instead of forwarding our own
+    /// stdin. At most [`MAX_STDIN_LEN`] bytes. This is for embedders, e.g.
+    /// through [`LaunchBuilder::stdin`], to hand a command a small input such
+    /// as a config file. The krun command never sets it and streams a piped
+    /// stdin instead, which works for input of any size.
+    pub stdin: Vec<u8>,
+    /// Linux capabilities to take away from the command. The launch fails if
+    /// the server is too old to do so, instead of running the command with all
+    /// of them.
+    pub capabilities: Option<Capabilities>,
+    /// Leave the command with the umask of the server, instead of ours.
+    pub server_umask: bool,
+    /// File in the guest to write the stdout of the command to, e.g.
+    /// `/dev/null`, instead of receiving it. As the microVM shares the root
+    /// filesystem of the host, that's usually the same file on the host.
+    pub stdout_path: Option<PathBuf>,
+    /// File in the guest to write the stderr of the command to, as with
+    /// `stdout_path`.
+    pub stderr_path: Option<PathBuf>,
+    /// Append to `stdout_path` and `stderr_path` rather than truncate them.
+    pub append_output: bool,
+    /// Send the stderr of the command wherever its stdout goes, as with `2>&1`.
+    /// Can't be combined with `stderr_path`.
+    pub merge_stderr: bool,
+    /// Send the SIGINT and SIGTERM we get to the command while it runs, rather
+    /// than let them terminate us. On a second one, the launch fails with
+    /// [`LaunchError::Interrupted`]. This blocks them on the calling thread
+    /// meanwhile and so only works if no other thread of the process takes
+    /// them, as in the krun command, which sets it.
+    pub forward_signals: bool,
+}
+
+/// Exit code of commands killed for running past their timeout, as with
+/// timeout(1).
+pub const TIMED_OUT_EXIT_CODE: i32 = 124;
+
+/// Returns whether krun runs interactively, that is with its stdin, stdout
+/// and stderr all connected to a terminal. Callers can use this as the
+/// default of their own interactive behavior, keeping an option for automation
+/// to force it either way. [`launch_or_lock`] needs no such flag, it forwards
+/// our stdin whenever it's not a terminal.
+pub fn detect_interactive() -> bool {
+    all_terminals(&[
+        io::stdin().as_fd(),
+        io::stdout().as_fd(),
+        io::stderr().as_fd(),
+    ])
+}
+
+fn all_terminals(fds: &[BorrowedFd]) -> bool {
+    fds.iter().all(|fd| fd.is_terminal())
+}
+
+/// Requests the running krun instance to launch `command`, or acquires the
+/// lock for starting the microVM if there's none. See [`LaunchBuilder`] for a
+/// more convenient way to call this.
 pub fn launch_or_lock(
     server_port: u32,
     command: PathBuf,
     command_args: Vec<String>,
-    env: Vec<(String, Option<String>)>,
+    env: Vec<(String, EnvValue)>,
+    env_file: Option<&Path>,
+    options: LaunchOptions,
 ) -> Result<LaunchResult> {
-    let running_server_port = env::var("KRUN_SERVER_PORT").ok();
-    if let Some(port) = running_server_port {
-        let port: u32 = port.parse()?;
-        let env = prepare_env_vars(env)?;
-        if let Err(err) = request_launch(port, command, command_args, env) {
-            return Err(anyhow!("could not request launch to server: {err}"));
-        }
-        return Ok(LaunchResult::LaunchRequested);
+    if env::var_os(INSIDE_VM_ENV_VAR).is_some() {
+        return Err(anyhow!(
+            "already inside a krun VM, run the command directly instead"
+        ));
+    }
+
+    check_command_encoding(&command, &command_args)?;
+    check_command(&command, options.cwd.as_deref(), options.strict)?;
+
+    // Env vars given explicitly take precedence over the ones from the file.
+    let env = match env_file {
+        Some(path) => read_env_file(path)?.into_iter().chain(env).collect(),
+        None => env,
+    };
+
+    let command_args = if options.expand_args {
+        command_args
+            .iter()
+            .map(|arg| expand_env_vars(arg))
+            .collect::<Result<_>>()?
+    } else {
+        command_args
+    };
+
+    if options.dry_run {
+        let launch = prepare_launch(command, command_args, env, options)?;
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&launch).map_err(LaunchError::Json)?
+        );
+        return Ok(LaunchResult::DryRun);
+    }
+
+    let connect_timeout = connect_timeout()?;
+    let connect_retries = connect_retries()?;
+    let cancel = options.cancel.clone();
+    let forward_signals = options.forward_signals;
+    check_cancelled(cancel.as_deref())?;
+
+    if let Some(addr) = server_override_addr()? {
+        let port = addr.port().into();
+        let launch = prepare_launch(command, command_args, env, options)?;
+        check_cancelled(cancel.as_deref())?;
+        return request_launch(
+            addr,
+            &launch,
+            connect_timeout,
+            RESPONSE_TIMEOUT,
+            cancel.as_deref(),
+            forward_signals,
+        )
+        .context(LaunchFailure { port, retries: 0 });
+    }
+
+    // A krun server started by our parent reports its port over a pipe, see
+    // `--port-fd`, which avoids racing with it over the lock file while it
+    // starts up. The fd is ours once read, so our children mustn't try again.
+    if let Ok(fd) = env::var("KRUN_PORT_FD") {
+        env::remove_var("KRUN_PORT_FD");
+        let port = read_port_fd(&fd)?;
+        debug!(fd:%, port; "read server port from fd");
+        let addr = server_addr(server_host()?, port)?;
+        let launch = prepare_launch(command, command_args, env, options)?;
+        return request_launch_with_retries(
+            addr,
+            &launch,
+            connect_timeout,
+            connect_retries,
+            cancel.as_deref(),
+            forward_signals,
+        );
     }
 
+    let started = Instant::now();
     let (lock_file, running_server_port) = lock_file(server_port)?;
+    debug!(
+        port = running_server_port.unwrap_or(server_port),
+        acquired:? = lock_file.as_ref().map(|(_, reason)| reason),
+        elapsed_ms = elapsed_ms(started);
+        "checked lock file"
+    );
     match lock_file {
-        Some(lock_file) => Ok(LaunchResult::LockAcquired {
+        Some((lock_file, reason)) => Ok(LaunchResult::LockAcquired {
             lock_file,
+            reason,
             command,
             command_args,
             env,
         }),
         None => {
             if let Some(port) = running_server_port {
-                let env = prepare_env_vars(env)?;
-                let mut tries = 0;
-                loop {
-                    match request_launch(port, command.clone(), command_args.clone(), env.clone()) {
-                        Err(err) => match err.downcast_ref::<LaunchError>() {
-                            Some(&LaunchError::Connection(_)) => {
-                                if tries == 3 {
-                                    return Err(anyhow!(
-                                        "could not request launch to server: {err}"
-                                    ));
-                                } else {
-                                    tries += 1;
-                                }
-                            },
-                            _ => {
-                                return Err(anyhow!("could not request launch to server: {err}"));
-                            },
-                        },
-                        Ok(_) => return Ok(LaunchResult::LaunchRequested),
-                    }
-                }
+                let addr = lock_server_addr(port)?;
+                let launch = prepare_launch(command, command_args, env, options)?;
+                request_launch_with_retries(
+                    addr,
+                    &launch,
+                    connect_timeout,
+                    connect_retries,
+                    cancel.as_deref(),
+                    forward_signals,
+                )
             } else {
                 Err(anyhow!(
                     "krun is already running but couldn't find its server port, bailing out"
@@ -105,16 +690,450 @@ pub fn launch_or_lock(
     }
 }
 
-fn lock_file(server_port: u32) -> Result<(Option<File>, Option<u32>)> {
-    let run_path = env::var("XDG_RUNTIME_DIR")
-        .context("Failed to read XDG_RUNTIME_DIR environment variable")?;
-    let lock_path = Path::new(&run_path).join("krun.lock");
+/// Requests a launch from the server at `addr`, retrying to connect a few
+/// times if the server can't be reached, as it may still be starting. Nothing
+/// is retried once the request is sent, as the server may have started the
+/// command by then. Gives up before any attempt once `cancel` is set.
+fn request_launch_with_retries(
+    addr: SocketAddr,
+    launch: &Launch,
+    connect_timeout: Duration,
+    max_retries: u32,
+    cancel: Option<&AtomicBool>,
+    forward_signals: bool,
+) -> Result<LaunchResult> {
+    let port = addr.port().into();
+    let mut tries = 0;
+    let stream = loop {
+        check_cancelled(cancel).context(LaunchFailure {
+            port,
+            retries: tries,
+        })?;
+        match connect_server(addr, connect_timeout) {
+            Ok(stream) => break stream,
+            Err(err) if tries >= max_retries => {
+                let attempts = tries + 1;
+                return Err(anyhow::Error::from(err)
+                    .context(format!(
+                        "gave up after {attempts} attempt{}",
+                        if attempts == 1 { "" } else { "s" }
+                    ))
+                    .context(LaunchFailure {
+                        port,
+                        retries: tries,
+                    }));
+            },
+            Err(err) => {
+                tries += 1;
+                debug!(port, attempt = tries, err:%; "connect attempt failed, retrying");
+            },
+        }
+    };
+    launch_request_payload(addr, launch, connect_timeout)
+        .and_then(|payload| {
+            check_cancelled(cancel)?;
+            request_launch_over(
+                stream,
+                addr,
+                launch,
+                &payload,
+                RESPONSE_TIMEOUT,
+                cancel,
+                forward_signals,
+            )
+        })
+        .context(LaunchFailure {
+            port,
+            retries: tries,
+        })
+}
+
+/// Runs `f` on `stream`, a connection to the server, shutting the connection
+/// down if `cancel` is set in the meantime, so that whatever `f` blocks on
+/// returns promptly and the launch fails with [`LaunchError::Cancelled`].
+fn until_cancelled<T, F>(stream: TcpStream, cancel: Option<&AtomicBool>, f: F) -> Result<T>
+where
+    F: FnOnce(TcpStream) -> Result<T>,
+{
+    let Some(cancel) = cancel else {
+        return f(stream);
+    };
+    let watched = stream.try_clone().map_err(LaunchError::Connection)?;
+    let done = (Mutex::new(false), Condvar::new());
+    let res = thread::scope(|scope| {
+        scope.spawn(|| {
+            let (done, wake) = &done;
+            let mut done = done.lock().unwrap();
+            while !*done {
+                if cancel.load(Ordering::Relaxed) {
+                    debug!("launch cancelled, closing connection to server");
+                    watched.shutdown(Shutdown::Both).ok();
+                    return;
+                }
+                done = wake.wait_timeout(done, CANCEL_POLL_INTERVAL).unwrap().0;
+            }
+        });
+        let res = f(stream);
+        *done.0.lock().unwrap() = true;
+        done.1.notify_one();
+        res
+    });
+    match res {
+        Err(_) if cancel.load(Ordering::Relaxed) => Err(LaunchError::Cancelled.into()),
+        res => res,
+    }
+}
+
+fn check_cancelled(cancel: Option<&AtomicBool>) -> Result<(), LaunchError> {
+    match cancel {
+        Some(cancel) if cancel.load(Ordering::Relaxed) => Err(LaunchError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// Makes sure `command` and `command_args` can be sent to the server and
+/// passed to exec there, that is they are valid UTF-8 without NUL characters.
+fn check_command_encoding(command: &Path, command_args: &[String]) -> Result<()> {
+    let path = command.to_str().with_context(|| {
+        format!("Failed to process command {command:?} as it contains invalid UTF-8")
+    })?;
+    if path.contains('\0') {
+        return Err(anyhow!(
+            "Failed to process command {command:?} as it contains NUL character"
+        ));
+    }
+    if let Some(arg) = command_args.iter().find(|arg| arg.contains('\0')) {
+        return Err(anyhow!(
+            "Failed to process argument {arg:?} as it contains NUL character"
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `command`, if it's a path rather than a name to look up in
+/// `PATH`, exists on the host. The microVM uses the host root filesystem, so a
+/// missing path is most likely a mistake, but the guest may still have it
+/// (e.g. in a tmpfs), so it only gets a warning unless `strict`. A relative
+/// path is relative to `cwd`, or else the current directory.
+fn check_command(command: &Path, cwd: Option<&Path>, strict: bool) -> Result<()> {
+    if command.components().count() < 2 {
+        return Ok(());
+    }
+    let path = match cwd {
+        Some(cwd) => cwd.join(command),
+        None => env::current_dir()
+            .context("Failed to get current working directory")?
+            .join(command),
+    };
+    if path.exists() {
+        return Ok(());
+    }
+    if strict {
+        return Err(anyhow!("Command {command:?} does not exist"));
+    }
+    eprintln!("Warning: command {command:?} does not exist on the host");
+    Ok(())
+}
+
+fn prepare_launch(
+    command: PathBuf,
+    command_args: Vec<String>,
+    env: Vec<(String, EnvValue)>,
+    options: LaunchOptions,
+) -> Result<Launch> {
+    let env = prepare_env_vars(env)?;
+    let cwd = match options.cwd {
+        Some(cwd) => cwd,
+        None => env::current_dir().context("Failed to get current working directory")?,
+    };
+    if options.stdin.len() > MAX_STDIN_LEN {
+        return Err(anyhow!(
+            "stdin of {} bytes is over the limit of {MAX_STDIN_LEN} bytes, forward it as a stream \
+             instead",
+            options.stdin.len()
+        ));
+    }
+    // Only a piped or redirected stdin is forwarded, as there's no way for the
+    // launched command to interact with a terminal.
+    let forward_stdin = options.stdin.is_empty() && !options.detach && !io::stdin().is_terminal();
+
+    let timeout_ms = options
+        .timeout
+        .map(|timeout| u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX));
+    let idle_timeout_ms = options
+        .idle_timeout
+        .filter(|_| !options.detach)
+        .map(|timeout| u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX));
+
+    // As a shell would do, rather than the full path of the command.
+    let argv0 = options.argv0.or_else(|| {
+        command
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    });
+
+    Ok(Launch {
+        command,
+        command_args,
+        env,
+        cwd,
+        forward_stdin,
+        timeout_ms,
+        user: options.user,
+        detach: options.detach,
+        argv0,
+        fail_if_busy: options.fail_if_busy,
+        clean_env: options.clean_env,
+        stdin: options.stdin,
+        // Lets the server reap the command if we get killed.
+        kill_on_disconnect: !options.detach,
+        idle_timeout_ms,
+        capabilities: options.capabilities,
+        umask: (!options.server_umask).then(current_umask),
+        stdout_path: options.stdout_path,
+        stderr_path: options.stderr_path,
+        append_output: options.append_output,
+        merge_stderr: options.merge_stderr,
+    })
+}
+
+/// Returns the umask of this process, from `/proc/self/status`. Without it,
+/// e.g. before Linux 4.7, the umask can only be read by setting it, so it's
+/// briefly cleared, which could affect the permissions of files created by
+/// other threads in the meantime.
+fn current_umask() -> u32 {
+    if let Some(mask) = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| parse_status_umask(&status))
+    {
+        return mask;
+    }
+    let mask = umask(Mode::empty());
+    umask(mask);
+    mask.bits()
+}
+
+/// Parses the `Umask:` line of `/proc/<pid>/status`, an octal number.
+fn parse_status_umask(status: &str) -> Option<u32> {
+    let mask = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))?;
+    u32::from_str_radix(mask.trim(), 8).ok()
+}
+
+fn parse_server_port(port: &str) -> Result<u32> {
+    let port: u32 = port
+        .parse()
+        .with_context(|| format!("Failed to parse `KRUN_SERVER_PORT` value {port:?}"))?;
+    if port <= 1024 || port > 65535 {
+        return Err(anyhow!(
+            "`KRUN_SERVER_PORT` value {port} is out of range, it must be between 1025 and 65535"
+        ));
+    }
+    Ok(port)
+}
+
+/// Reads the server port from the inherited file descriptor `fd`, as a line
+/// of text. Blocks until the server writes it, or fails if the server closes
+/// its end of the pipe first.
+fn read_port_fd(fd: &str) -> Result<u32> {
+    let fd: RawFd = fd
+        .parse()
+        .with_context(|| format!("Invalid `KRUN_PORT_FD` value {fd:?}"))?;
+    let file = File::from(take_inherited_fd(fd, "`KRUN_PORT_FD`")?);
+
+    let mut line = String::new();
+    BufReader::new(file)
+        .read_line(&mut line)
+        .with_context(|| format!("Failed to read server port from `KRUN_PORT_FD` {fd}"))?;
+    if line.is_empty() {
+        return Err(anyhow!(
+            "`KRUN_PORT_FD` {fd} was closed before the server port was written"
+        ));
+    }
+    let port = line.trim();
+    port.parse::<u32>()
+        .ok()
+        .filter(|port| (1025..=65535).contains(port))
+        .with_context(|| format!("Invalid server port {port:?} read from `KRUN_PORT_FD` {fd}"))
+}
+
+/// Writes `port` to the inherited file descriptor `fd` as a line of text and
+/// closes it, for `--port-fd`, so that a krun given the other end of the pipe
+/// in `KRUN_PORT_FD` can launch commands on this server.
+pub fn write_port_fd(fd: RawFd, port: u32) -> Result<()> {
+    let mut file = File::from(take_inherited_fd(fd, "`--port-fd`")?);
+    writeln!(file, "{port}")
+        .with_context(|| format!("Failed to write server port to `--port-fd` {fd}"))
+}
+
+/// Takes ownership of `fd`, which `what` says was inherited from the parent
+/// process. The standard streams are refused, as are fds with close-on-exec
+/// set, which can't have been inherited and so belong to this process already.
+fn take_inherited_fd(fd: RawFd, what: &str) -> Result<OwnedFd> {
+    if fd <= 2 {
+        return Err(anyhow!(
+            "{what} {fd} is not an inherited file descriptor above the standard streams"
+        ));
+    }
+    // SAFETY: The fd is only borrowed to check that it is open.
+    let flags = fcntl_getfd(unsafe { BorrowedFd::borrow_raw(fd) })
+        .with_context(|| format!("{what} {fd} is not an open file descriptor"))?;
+    if flags.contains(FdFlags::CLOEXEC) {
+        return Err(anyhow!(
+            "{what} {fd} is not an inherited file descriptor, it is close-on-exec"
+        ));
+    }
+    // SAFETY: The fd is open and was inherited, as it would have been opened
+    // with close-on-exec otherwise, so nothing else in this process owns it.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Returns where to launch commands if `KRUN_SERVER_PORT` is set: on that
+/// port of [`server_host`], bypassing the lock file.
+pub(crate) fn server_override_addr() -> Result<Option<SocketAddr>> {
+    let Ok(port) = env::var("KRUN_SERVER_PORT") else {
+        return Ok(None);
+    };
+    let port = parse_server_port(&port)?;
+    Ok(Some(server_addr(server_host()?, port)?))
+}
+
+/// Returns the address of the server of the krun instance holding the lock,
+/// which listens on `port` of [`DEFAULT_SERVER_HOST`], as `KRUN_SERVER_HOST`
+/// only applies together with `KRUN_SERVER_PORT`.
+pub(crate) fn lock_server_addr(port: u32) -> Result<SocketAddr, LaunchError> {
+    server_addr(DEFAULT_SERVER_HOST, port)
+}
+
+pub(crate) fn server_host() -> Result<IpAddr> {
+    match env::var("KRUN_SERVER_HOST") {
+        Ok(host) => parse_server_host(&host),
+        Err(_) => Ok(DEFAULT_SERVER_HOST),
+    }
+}
+
+fn parse_server_host(host: &str) -> Result<IpAddr> {
+    // Also accept IPv6 addresses in brackets, as in URLs.
+    let addr = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    addr.parse()
+        .with_context(|| format!("Failed to parse `KRUN_SERVER_HOST` value {host:?}"))
+}
+
+pub(crate) fn server_addr(host: IpAddr, port: u32) -> Result<SocketAddr, LaunchError> {
+    let port = u16::try_from(port).map_err(|_| {
+        LaunchError::Connection(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid server port {port}"),
+        ))
+    })?;
+    Ok(SocketAddr::new(host, port))
+}
+
+pub(crate) fn connect_timeout() -> Result<Duration> {
+    match env::var("KRUN_CONNECT_TIMEOUT") {
+        Ok(secs) => {
+            let secs: u64 = secs.parse().with_context(|| {
+                format!("Failed to parse `KRUN_CONNECT_TIMEOUT` value {secs:?}")
+            })?;
+            if secs == 0 {
+                return Err(anyhow!("`KRUN_CONNECT_TIMEOUT` must be greater than 0"));
+            }
+            Ok(Duration::from_secs(secs))
+        },
+        Err(_) => Ok(CONNECT_TIMEOUT),
+    }
+}
+
+fn connect_retries() -> Result<u32> {
+    match env::var("KRUN_CONNECT_RETRIES") {
+        Ok(retries) => parse_connect_retries(&retries),
+        Err(_) => Ok(CONNECT_RETRIES),
+    }
+}
+
+fn parse_connect_retries(retries: &str) -> Result<u32> {
+    let retries: u32 = retries
+        .parse()
+        .with_context(|| format!("Failed to parse `KRUN_CONNECT_RETRIES` value {retries:?}"))?;
+    if retries > MAX_CONNECT_RETRIES {
+        return Err(anyhow!(
+            "`KRUN_CONNECT_RETRIES` value {retries} is out of range, it must be at most \
+             {MAX_CONNECT_RETRIES}"
+        ));
+    }
+    Ok(retries)
+}
+
+/// Returns the port of the server of the running krun instance, if any,
+/// without launching anything or acquiring the lock.
+pub fn server_status() -> Result<Option<u32>> {
+    server_status_at(&lock_path()?)
+}
+
+pub(crate) fn server_status_at(lock_path: &Path) -> Result<Option<u32>> {
+    let data = match fs::read_to_string(lock_path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context("Failed to read lock file"),
+    };
+    // A stale lock file may be left behind by a krun instance that is gone, so
+    // only report servers that are actually accepting connections.
+    Ok(parse_lock_data(&data)
+        .map(|(_pid, port)| port)
+        .filter(|&port| server_alive(port)))
+}
+
+/// Returns the path of the lock file: `KRUN_LOCK_PATH` if set, otherwise
+/// `krun-$KRUN_INSTANCE.lock` in the runtime dir if `KRUN_INSTANCE` is set, so
+/// that independent krun instances can run side by side, or else `krun.lock`
+/// there.
+pub(crate) fn lock_path() -> Result<PathBuf> {
+    if let Some(path) = env::var_os("KRUN_LOCK_PATH") {
+        return Ok(PathBuf::from(path));
+    }
+    let instance = env::var("KRUN_INSTANCE").ok();
+    instance_lock_path(&runtime_dir()?, instance.as_deref())
+}
+
+fn instance_lock_path(runtime_dir: &Path, instance: Option<&str>) -> Result<PathBuf> {
+    match instance {
+        None => Ok(runtime_dir.join("krun.lock")),
+        Some(instance) if instance.is_empty() || instance.contains(['/', '\0']) => {
+            Err(anyhow!("Invalid `KRUN_INSTANCE` value {instance:?}"))
+        },
+        Some(instance) => Ok(runtime_dir.join(format!("krun-{instance}.lock"))),
+    }
+}
+
+type LockStatus = (Option<(File, LockReason)>, Option<u32>);
+
+fn lock_file(server_port: u32) -> Result<LockStatus> {
+    lock_file_at(&lock_path()?, server_port)
+}
 
-    let mut lock_file = if !lock_path.exists() {
+/// Takes the lock at `lock_path`, or returns the port of the server of the
+/// krun instance holding it.
+fn lock_file_at(lock_path: &Path, server_port: u32) -> Result<LockStatus> {
+    let exists = match fs::metadata(lock_path) {
+        Ok(metadata) if metadata.is_file() => true,
+        Ok(metadata) => {
+            remove_stale_lock_path(lock_path, &metadata)?;
+            false
+        },
+        Err(err) if err.kind() == ErrorKind::NotFound => false,
+        Err(err) => return Err(err).context("Failed to check lock file"),
+    };
+    // If the lock file exists but nobody holds the lock, the krun instance
+    // that created it is gone and we simply take over the lock below.
+    let (mut lock_file, reason) = if !exists {
         let lock_file = File::create(lock_path).context("Failed to create lock file")?;
         flock(&lock_file, FlockOperation::NonBlockingLockExclusive)
             .context("Failed to acquire exclusive lock on new lock file")?;
-        lock_file
+        (lock_file, LockReason::FirstStart)
     } else {
         let mut lock_file = File::options()
             .write(true)
@@ -123,64 +1142,2269 @@ fn lock_file(server_port: u32) -> Result<(Option<File>, Option<u32>)> {
             .context("Failed to create lock file")?;
         let ret = flock(&lock_file, FlockOperation::NonBlockingLockExclusive);
         if ret.is_err() {
-            let mut data: Vec<u8> = Vec::with_capacity(5);
-            lock_file.read_to_end(&mut data)?;
-            let port = match data.to_string_lossy().parse::<u32>() {
-                Ok(port) => {
-                    if port > 1024 {
-                        Some(port)
-                    } else {
-                        None
-                    }
-                },
-                Err(_) => None,
-            };
+            let port = read_lock_port(&mut lock_file)?;
+            // The lock is held by a running krun instance, but make sure its
+            // server is there before handing out its port.
+            if let Some(port) = port {
+                if !wait_for_server(port) {
+                    return Err(anyhow!(
+                        "krun is already running but its server on port {port} is not \
+                         responding, bailing out"
+                    ));
+                }
+            }
             return Ok((None, port));
         }
-        lock_file
+        (lock_file, LockReason::TookOverStale)
     };
 
     lock_file.set_len(0)?;
-    lock_file.write_all(format!("{server_port}").as_bytes())?;
-    Ok((Some(lock_file), None))
+    lock_file.write_all(format!("{} {server_port}\n", process::id()).as_bytes())?;
+    Ok((Some((lock_file, reason)), None))
 }
 
-fn request_launch(
-    server_port: u32,
-    command: PathBuf,
-    command_args: Vec<String>,
-    env: HashMap<String, String>,
-) -> Result<()> {
-    let mut stream =
-        TcpStream::connect(format!("127.0.0.1:{server_port}")).map_err(LaunchError::Connection)?;
+/// Removes what's in the way of creating the lock file at `lock_path` if it's
+/// safe to, that is an empty directory, a fifo or a socket, none of which
+/// could hold the port of a running krun instance.
+fn remove_stale_lock_path(lock_path: &Path, metadata: &fs::Metadata) -> Result<()> {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        fs::remove_dir(lock_path).with_context(|| {
+            format!("Lock path {lock_path:?} is a directory that can't be removed")
+        })?;
+    } else if file_type.is_fifo() || file_type.is_socket() {
+        fs::remove_file(lock_path)
+            .with_context(|| format!("Failed to remove {lock_path:?} in the way of lock file"))?;
+    } else {
+        return Err(anyhow!("Lock path {lock_path:?} is not a regular file"));
+    }
+    debug!(lock_path:?, file_type:?; "removed stale lock path");
+    Ok(())
+}
 
-    let launch = Launch {
-        command,
-        command_args,
-        env,
+/// Reads the server port from a lock file held by another krun instance. That
+/// instance may have just taken the lock and not finished writing its port
+/// yet, so an empty or partially written lock file is re-read a few times
+/// before giving up.
+fn read_lock_port(lock_file: &mut File) -> Result<Option<u32>> {
+    let mut previous: Option<Vec<u8>> = None;
+    for attempt in 0..LOCK_READ_TRIES {
+        if attempt > 0 {
+            thread::sleep(LOCK_READ_INTERVAL);
+        }
+        let mut data: Vec<u8> = Vec::with_capacity(16);
+        lock_file.rewind()?;
+        lock_file.read_to_end(&mut data)?;
+        match parse_lock_data(&data.to_string_lossy()) {
+            Some((Some(_pid), port)) => return Ok(Some(port)),
+            // A bare `PORT` looks just like the PID of a `PID PORT` line
+            // still being written, so only trust it once it reads the same
+            // twice.
+            Some((None, port)) if previous.as_ref() == Some(&data) => return Ok(Some(port)),
+            _ => debug!(data:? = data; "lock file has no valid server port yet"),
+        }
+        previous = Some(data);
+    }
+    Ok(None)
+}
+
+/// Parses the contents of the lock file, which is a `PID PORT` line or, as
+/// written by older versions of krun, just `PORT` without a newline. A `PID
+/// PORT` line is only complete once its newline is there, so that a port
+/// still being written is not cut short.
+pub(crate) fn parse_lock_data(data: &str) -> Option<(Option<u32>, u32)> {
+    let (line, terminated) = match data.split_once('\n') {
+        Some((line, _)) => (line, true),
+        None => (data, false),
+    };
+    let (pid, port) = match line.split_once(' ') {
+        Some((pid, port)) if terminated => (Some(pid.parse::<u32>().ok()?), port),
+        Some(_) => return None,
+        None => (None, line),
     };
+    let port = port.parse::<u16>().ok()?;
+    if port > 1024 {
+        Some((pid, port.into()))
+    } else {
+        None
+    }
+}
 
-    stream
-        .write_all(
-            serde_json::to_string(&launch)
-                .map_err(LaunchError::Json)?
-                .as_bytes(),
-        )
-        .map_err(LaunchError::Connection)?;
-    stream
-        .write_all(b"\nEOM\n")
-        .map_err(LaunchError::Connection)?;
-    stream.flush().map_err(LaunchError::Connection)?;
+/// Checks whether the server on `server_port` accepts connections, retrying
+/// with backoff, see [`LIVENESS_TRIES`].
+fn wait_for_server(server_port: u32) -> bool {
+    let mut interval = LIVENESS_RETRY_INTERVAL;
+    for attempt in 0..LIVENESS_TRIES {
+        if attempt > 0 {
+            thread::sleep(interval);
+            interval *= 2;
+        }
+        if server_alive(server_port) {
+            return true;
+        }
+        debug!(port = server_port, attempt; "server not answering yet");
+    }
+    false
+}
 
-    let mut buf_reader = BufReader::new(&mut stream);
-    let mut resp = String::new();
-    buf_reader
+fn server_alive(server_port: u32) -> bool {
+    let Ok(addr) = lock_server_addr(server_port) else {
+        return false;
+    };
+    connect(addr, LIVENESS_TIMEOUT).is_ok()
+}
+
+/// Asks the krun server for its protocol version and uptime, without
+/// launching anything.
+pub fn ping(server_port: u32) -> Result<ServerInfo> {
+    let addr = server_addr(server_host()?, server_port)?;
+    Ok(request_server_info(addr, connect_timeout()?)?)
+}
+
+pub(crate) fn request_server_info(
+    addr: SocketAddr,
+    connect_timeout: Duration,
+) -> Result<ServerInfo, LaunchError> {
+    let mut stream = send_request(addr, &Request::Ping, connect_timeout)?;
+    let mut resp = String::new();
+    BufReader::new(&mut stream)
+        .read_line(&mut resp)
+        .map_err(LaunchError::Connection)?;
+    serde_json::from_str(&resp)
+        .map_err(|_| LaunchError::Server(format!("invalid ping response {resp:?}")))
+}
+
+/// Makes sure the server at `addr` applies [`Launch::capabilities`], as older
+/// ones ignore it and would run the command with all of its capabilities.
+fn check_capabilities_support(
+    addr: SocketAddr,
+    connect_timeout: Duration,
+) -> Result<(), LaunchError> {
+    let info = request_server_info(addr, connect_timeout)?;
+    if info.protocol_version < CAPABILITIES_PROTOCOL_VERSION {
+        return Err(LaunchError::Server(format!(
+            "protocol version {} is too old to restrict capabilities, {} is needed",
+            info.protocol_version, CAPABILITIES_PROTOCOL_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// Asks the krun server for the launches whose command is still running.
+pub fn list_launches(server_port: u32) -> Result<Vec<LaunchInfo>> {
+    let addr = server_addr(server_host()?, server_port)?;
+    let mut stream = send_request(addr, &Request::ListLaunches, connect_timeout()?)?;
+    let mut resp = String::new();
+    BufReader::new(&mut stream)
+        .read_line(&mut resp)
+        .map_err(LaunchError::Connection)?;
+    let launches = serde_json::from_str(&resp)
+        .map_err(|_| LaunchError::Server(format!("invalid launch list {resp:?}")))?;
+    Ok(launches)
+}
+
+/// Asks the krun server to run `launches` one after the other in a single
+/// request, and returns how each went once they're done. The launches are sent
+/// as is, their env vars aren't resolved from the local environment as in
+/// [`launch_or_lock`], and they run detached with their output logged to a
+/// file in the microVM.
+pub fn request_launch_batch(
+    server_port: u32,
+    launches: Vec<Launch>,
+    policy: BatchPolicy,
+) -> Result<Vec<BatchStatus>> {
+    let addr = server_addr(server_host()?, server_port)?;
+    let count = launches.len();
+    let request = Request::LaunchBatch { launches, policy };
+    let mut stream = send_request(addr, &request, connect_timeout()?)?;
+    // The batch may run for as long as its commands want.
+    let mut resp = String::new();
+    BufReader::new(&mut stream)
         .read_line(&mut resp)
         .map_err(LaunchError::Connection)?;
+    let statuses: Vec<BatchStatus> = serde_json::from_str(&resp)
+        .map_err(|_| LaunchError::Server(format!("invalid batch response {resp:?}")))?;
+    if statuses.len() != count {
+        return Err(LaunchError::Server(format!(
+            "expected {count} batch statuses, got {}",
+            statuses.len()
+        ))
+        .into());
+    }
+    Ok(statuses)
+}
 
-    if resp == "OK" {
-        Ok(())
+/// Asks the krun server to terminate the command of the launch `id`, as listed
+/// by [`list_launches`]. The command gets SIGTERM, then SIGKILL if it doesn't
+/// exit in time.
+pub fn kill_launch(server_port: u32, id: u64) -> Result<()> {
+    let addr = server_addr(server_host()?, server_port)?;
+    let mut stream = send_request(addr, &Request::KillLaunch { id }, connect_timeout()?)?;
+    let mut resp = String::new();
+    stream
+        .read_to_string(&mut resp)
+        .map_err(LaunchError::Connection)?;
+    parse_response(&resp)?;
+    Ok(())
+}
+
+/// Connects to the server at `addr`, falling back to the IPv6 loopback address
+/// if nothing listens on the IPv4 one, as loopback may be IPv6-only on some
+/// hosts.
+fn connect(addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    let err = match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(stream) => return Ok(stream),
+        // A connection timing out means something listens there, but isn't
+        // answering.
+        Err(err) if addr.ip() != DEFAULT_SERVER_HOST || err.kind() == ErrorKind::TimedOut => {
+            return Err(err);
+        },
+        Err(err) => err,
+    };
+
+    let fallback = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port());
+    debug!(addr:%, err:%; "failed to connect to server, trying IPv6 loopback");
+    TcpStream::connect_timeout(&fallback, timeout).map_err(|fallback_err| {
+        io::Error::new(
+            err.kind(),
+            format!("{addr}: {err}, {fallback}: {fallback_err}"),
+        )
+    })
+}
+
+fn send_request(
+    addr: SocketAddr,
+    request: &Request,
+    connect_timeout: Duration,
+) -> Result<TcpStream, LaunchError> {
+    let request = serde_json::to_vec(request).map_err(LaunchError::Json)?;
+    send_request_payload(addr, &request, connect_timeout)
+}
+
+/// Sends a request already serialized as JSON.
+fn send_request_payload(
+    addr: SocketAddr,
+    payload: &[u8],
+    connect_timeout: Duration,
+) -> Result<TcpStream, LaunchError> {
+    let started = Instant::now();
+    let mut stream = connect_server(addr, connect_timeout)?;
+    write_request_payload(&mut stream, addr, payload, started)?;
+    Ok(stream)
+}
+
+/// Connects to the server at `addr`, see [`connect`].
+fn connect_server(addr: SocketAddr, connect_timeout: Duration) -> Result<TcpStream, LaunchError> {
+    let started = Instant::now();
+    let stream = connect(addr, connect_timeout).map_err(|err| {
+        if err.kind() == ErrorKind::TimedOut {
+            LaunchError::Timeout(connect_timeout)
+        } else {
+            LaunchError::Connection(err)
+        }
+    })?;
+    trace!(port = addr.port(), elapsed_ms = elapsed_ms(started); "connected to server");
+    Ok(stream)
+}
+
+fn write_request_payload(
+    stream: &mut TcpStream,
+    addr: SocketAddr,
+    payload: &[u8],
+    started: Instant,
+) -> Result<(), LaunchError> {
+    write_frame(stream, FrameKind::Request, payload).map_err(LaunchError::Connection)?;
+    trace!(port = addr.port(), elapsed_ms = elapsed_ms(started); "sent request");
+    Ok(())
+}
+
+/// Writes a launch request about to be sent to `addr` to `path`, after a line
+/// saying where it goes, for debugging launches after the fact. The values of
+/// sensitive env vars are left out as in the logs, the file is only readable by
+/// the user and a symlink at `path` is refused rather than followed. Failing to
+/// do so is only logged, so that it doesn't get in the way of the launch.
+fn dump_request(path: &Path, addr: SocketAddr, launch: &Launch) {
+    let mut launch = launch.clone();
+    redact_env_values(&mut launch.env);
+    let dump = File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .custom_flags(OFlags::NOFOLLOW.bits() as i32)
+        .open(path)
+        .and_then(|mut file| {
+            writeln!(file, "# krun launch request to {addr} over tcp")?;
+            serde_json::to_writer(&mut file, &Request::Launch(launch))?;
+            Ok(())
+        });
+    if let Err(err) = dump {
+        debug!(path:?, err:%; "failed to dump launch request");
+    }
+}
+
+fn request_launch(
+    addr: SocketAddr,
+    launch: &Launch,
+    connect_timeout: Duration,
+    response_timeout: Duration,
+    cancel: Option<&AtomicBool>,
+    forward_signals: bool,
+) -> Result<LaunchResult> {
+    let payload = launch_request_payload(addr, launch, connect_timeout)?;
+    let stream = connect_server(addr, connect_timeout)?;
+    check_cancelled(cancel)?;
+    request_launch_over(
+        stream,
+        addr,
+        launch,
+        &payload,
+        response_timeout,
+        cancel,
+        forward_signals,
+    )
+}
+
+/// Requests `launch`, serialized as `payload`, over `stream`, a connection to
+/// the server at `addr`. Gives up if `cancel` is set before the server accepts
+/// the launch, see [`until_cancelled`]. With `forward_signals`, see
+/// [`LaunchOptions::forward_signals`].
+fn request_launch_over(
+    stream: TcpStream,
+    addr: SocketAddr,
+    launch: &Launch,
+    payload: &[u8],
+    response_timeout: Duration,
+    cancel: Option<&AtomicBool>,
+    forward_signals: bool,
+) -> Result<LaunchResult> {
+    let (mut buf_reader, id) = until_cancelled(stream, cancel, |stream| {
+        let mut buf_reader = send_launch_over(stream, addr, payload, response_timeout)?;
+        let id = launch
+            .detach
+            .then(|| {
+                read_launch_id(&mut buf_reader, |err| {
+                    read_response_error(err, response_timeout)
+                })
+            })
+            .transpose()?;
+        Ok((buf_reader, id))
+    })?;
+    if let Some(id) = id {
+        return Ok(LaunchResult::Detached { id });
+    }
+
+    // The command may run for as long as it wants from now on.
+    buf_reader
+        .get_ref()
+        .set_read_timeout(None)
+        .map_err(|err| LaunchError::Server(format!("failed to clear read timeout: {err}")))?;
+
+    let writer = buf_reader
+        .get_ref()
+        .try_clone()
+        .map(|stream| Arc::new(Mutex::new(stream)))
+        .map_err(|err| LaunchError::Server(format!("failed to clone connection: {err}")))?;
+    let signal_forwarder = forward_signals
+        .then(|| SignalForwarder::start(Arc::clone(&writer)))
+        .transpose()?;
+    if launch.idle_timeout_ms.is_some() {
+        let writer = Arc::clone(&writer);
+        thread::spawn(move || {
+            if let Err(err) = send_heartbeats(&writer) {
+                debug!(err:%; "stopped sending heartbeats");
+            }
+        });
+    }
+    if launch.forward_stdin {
+        thread::spawn(move || {
+            if let Err(err) = forward_stdin(&mut io::stdin().lock(), &writer) {
+                debug!(err:%; "stopped forwarding stdin");
+            }
+        });
+    }
+
+    let exit_code = relay_output(&mut buf_reader, &mut io::stdout(), &mut io::stderr());
+    if let Some(signal) = signal_forwarder.and_then(SignalForwarder::stop) {
+        return Err(LaunchError::Interrupted(signal).into());
+    }
+
+    Ok(LaunchResult::LaunchRequested {
+        exit_code: exit_code?,
+    })
+}
+
+/// Requests `launch` over `stream`, a connection to a krun server set up by
+/// the caller, and waits for the command as [`launch_or_lock`] does, writing
+/// its output to our stdout and stderr. It's up to the caller to set timeouts
+/// on `stream`, and to make sure the server is recent enough for
+/// [`Launch::capabilities`]. Neither our stdin nor the signals we get are
+/// forwarded to the command, as that takes a second handle on the connection.
+pub fn request_launch_on<S>(stream: S, launch: &Launch) -> Result<LaunchResult>
+where
+    S: Read + Write,
+{
+    let mut buf_reader = BufReader::new(stream);
+    let payload = launch_payload(launch)?;
+    write_frame(buf_reader.get_mut(), FrameKind::Request, &payload)
+        .map_err(LaunchError::Connection)?;
+    read_launch_response(&mut buf_reader, LaunchError::Connection, |_| Ok(()))?;
+
+    if launch.detach {
+        let id = read_launch_id(&mut buf_reader, LaunchError::Connection)?;
+        return Ok(LaunchResult::Detached { id });
+    }
+    let exit_code = relay_output(&mut buf_reader, &mut io::stdout(), &mut io::stderr())?;
+
+    Ok(LaunchResult::LaunchRequested { exit_code })
+}
+
+/// Which output of a launched command a chunk passed to the callback of
+/// [`stream_launch`] comes from.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Requests the running krun instance to launch `command`, as
+/// [`launch_or_lock`] does, but passes the output of the command to
+/// `on_output` as it arrives instead of writing it to our own stdout and
+/// stderr, and returns the exit code of the command. Nothing is forwarded to
+/// the command, neither our stdin nor the signals we get, so this suits
+/// embedding krun in a program with its own way of handling those.
+pub fn stream_launch<F>(
+    server_port: u32,
+    command: PathBuf,
+    command_args: Vec<String>,
+    env: Vec<(String, EnvValue)>,
+    options: LaunchOptions,
+    on_output: F,
+) -> Result<i32>
+where
+    F: FnMut(OutputStream, &[u8]),
+{
+    if options.detach {
+        return Err(anyhow!("The output of a detached launch can't be streamed"));
+    }
+    let addr = server_addr(server_host()?, server_port)?;
+    let launch = Launch {
+        forward_stdin: false,
+        kill_on_disconnect: false,
+        idle_timeout_ms: None,
+        ..prepare_launch(command, command_args, env, options)?
+    };
+    let mut buf_reader = send_launch(addr, &launch, connect_timeout()?, RESPONSE_TIMEOUT).context(
+        LaunchFailure {
+            port: server_port,
+            retries: 0,
+        },
+    )?;
+
+    buf_reader
+        .get_ref()
+        .set_read_timeout(None)
+        .map_err(|err| LaunchError::Server(format!("failed to clear read timeout: {err}")))?;
+
+    Ok(relay_output_to(&mut buf_reader, on_output)?)
+}
+
+/// Serializes the request for `launch`, failing if it's larger than the server
+/// accepts, or than `KRUN_MAX_REQUEST_SIZE` if set, rather than have the
+/// server drop the connection.
+fn launch_payload(launch: &Launch) -> Result<Vec<u8>> {
+    let payload =
+        serde_json::to_vec(&Request::Launch(launch.clone())).map_err(LaunchError::Json)?;
+    let max_len = max_request_len()?;
+    if payload.len() > max_len {
+        return Err(anyhow!(
+            "Launch request of {} bytes is over the limit of {max_len} bytes (see \
+             `KRUN_MAX_REQUEST_SIZE`), forward fewer env vars, e.g. with `KRUN_NO_FORWARD` or \
+             a shorter `KRUN_PASSTHROUGH`",
+            payload.len()
+        ));
+    }
+    Ok(payload)
+}
+
+fn max_request_len() -> Result<usize> {
+    match env::var("KRUN_MAX_REQUEST_SIZE") {
+        Ok(size) => parse_max_request_len(&size),
+        Err(_) => Ok(MAX_REQUEST_LEN as usize),
+    }
+}
+
+fn parse_max_request_len(size: &str) -> Result<usize> {
+    let size: usize = size
+        .parse()
+        .with_context(|| format!("Failed to parse `KRUN_MAX_REQUEST_SIZE` value {size:?}"))?;
+    if size > MAX_REQUEST_LEN as usize {
+        return Err(anyhow!(
+            "`KRUN_MAX_REQUEST_SIZE` value {size} is out of range, the server accepts at most \
+             {MAX_REQUEST_LEN} bytes"
+        ));
+    }
+    Ok(size)
+}
+
+/// Sends a launch request to the server at `addr` and waits for the server to
+/// accept it, after which the server sends the launch id of a detached launch,
+/// or the output of the command otherwise.
+fn send_launch(
+    addr: SocketAddr,
+    launch: &Launch,
+    connect_timeout: Duration,
+    response_timeout: Duration,
+) -> Result<BufReader<TcpStream>> {
+    let payload = launch_request_payload(addr, launch, connect_timeout)?;
+    let stream = connect_server(addr, connect_timeout)?;
+    send_launch_over(stream, addr, &payload, response_timeout)
+}
+
+/// Checks that the server at `addr` supports what `launch` needs, and
+/// serializes the request for it.
+fn launch_request_payload(
+    addr: SocketAddr,
+    launch: &Launch,
+    connect_timeout: Duration,
+) -> Result<Vec<u8>> {
+    if launch.capabilities.is_some() {
+        check_capabilities_support(addr, connect_timeout)?;
+    }
+    let payload = launch_payload(launch)?;
+    if let Some(path) = env::var_os("KRUN_DEBUG_DUMP") {
+        dump_request(Path::new(&path), addr, launch);
+    }
+    Ok(payload)
+}
+
+/// Sends a launch request already serialized as `payload` over `stream`, a
+/// connection to the server at `addr`, as [`send_launch`] does.
+fn send_launch_over(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    payload: &[u8],
+    response_timeout: Duration,
+) -> Result<BufReader<TcpStream>> {
+    let started = Instant::now();
+    write_request_payload(&mut stream, addr, payload, started)?;
+
+    stream
+        .set_read_timeout(Some(response_timeout))
+        .map_err(LaunchError::Connection)?;
+    let mut buf_reader = BufReader::new(stream);
+    read_launch_response(
+        &mut buf_reader,
+        |err| read_response_error(err, response_timeout),
+        |buf_reader| {
+            // There's no telling when a queued launch will be admitted.
+            buf_reader
+                .get_ref()
+                .set_read_timeout(None)
+                .map_err(|err| LaunchError::Server(format!("failed to clear read timeout: {err}")))
+        },
+    )?;
+    debug!(
+        port = addr.port(),
+        elapsed_ms = elapsed_ms(started);
+        "server accepted launch request"
+    );
+
+    Ok(buf_reader)
+}
+
+/// Reads how the server answers a launch request, until it accepts the launch.
+/// `on_queued` is called if the server queues the launch until it has a free
+/// slot for it.
+fn read_launch_response<R, E, Q>(
+    reader: &mut R,
+    map_read_err: E,
+    on_queued: Q,
+) -> Result<(), LaunchError>
+where
+    R: BufRead,
+    E: Fn(io::Error) -> LaunchError,
+    Q: FnOnce(&mut R) -> Result<(), LaunchError>,
+{
+    let mut resp = String::new();
+    reader.read_line(&mut resp).map_err(&map_read_err)?;
+    if resp.is_empty() {
+        // The server went away without answering, e.g. as it was being
+        // restarted. There's no telling whether it started the command.
+        return Err(LaunchError::Connection(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "connection closed before the server answered",
+        )));
+    }
+    if resp.trim_end() == "QUEUED" {
+        debug!("launch queued until the server has a free slot");
+        on_queued(reader)?;
+        resp.clear();
+        reader.read_line(&mut resp).map_err(&map_read_err)?;
+    }
+    if resp.trim_end() != "OK" {
+        // Error messages may span multiple lines, make sure we get all of it.
+        reader.read_to_string(&mut resp).map_err(&map_read_err)?;
+    }
+    parse_response(&resp)
+}
+
+fn elapsed_ms(since: Instant) -> u64 {
+    u64::try_from(since.elapsed().as_millis()).unwrap_or(u64::MAX)
+}
+
+fn read_response_error(err: io::Error, response_timeout: Duration) -> LaunchError {
+    if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+        LaunchError::ResponseTimeout(response_timeout)
     } else {
-        Err(LaunchError::Server(resp).into())
+        LaunchError::Connection(err)
+    }
+}
+
+/// Reads the id the server answers a detached launch request with.
+fn read_launch_id<R, F>(reader: &mut R, map_read_err: F) -> Result<u64, LaunchError>
+where
+    R: Read,
+    F: Fn(io::Error) -> LaunchError,
+{
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    reader.read_exact(&mut header).map_err(&map_read_err)?;
+    match parse_frame_header(&header) {
+        (Ok(FrameKind::LaunchId), 8) => {
+            let mut id = [0u8; 8];
+            reader.read_exact(&mut id).map_err(&map_read_err)?;
+            Ok(u64::from_be_bytes(id))
+        },
+        (kind, len) => Err(LaunchError::Server(format!(
+            "expected launch id, got frame {kind:?} of {len} bytes"
+        ))),
+    }
+}
+
+/// Sends SIGINT and SIGTERM to the launched command instead of letting them
+/// terminate krun, so that e.g. Ctrl-C doesn't leave the command running in the
+/// microVM, see [`LaunchOptions::forward_signals`]. On a second signal, or if
+/// the first can't be sent, the connection is shut down to give up on the
+/// command.
+struct SignalForwarder {
+    /// Signal mask of the launching thread before the signals were blocked.
+    old_mask: SigSet,
+    stopping: Arc<AtomicBool>,
+    /// Returns the signal the command was given up on for, if any.
+    thread: thread::JoinHandle<Option<Signal>>,
+}
+
+impl SignalForwarder {
+    fn start(writer: Arc<Mutex<TcpStream>>) -> Result<Self, LaunchError> {
+        let mut signals = SigSet::empty();
+        signals.add(Signal::SIGINT);
+        signals.add(Signal::SIGTERM);
+        // Block the signals before spawning any thread, so that they're only
+        // ever picked up by the thread waiting for them.
+        let old_mask = signals
+            .thread_swap_mask(SigmaskHow::SIG_BLOCK)
+            .map_err(|err| {
+                LaunchError::Io(io::Error::new(
+                    io::Error::from(err).kind(),
+                    format!("failed to block signals: {err}"),
+                ))
+            })?;
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stopping = Arc::clone(&stopping);
+            move || {
+                let mut forwarded = false;
+                loop {
+                    let signal = signals.wait().ok()?;
+                    if stopping.load(Ordering::Relaxed) {
+                        return None;
+                    }
+                    if forwarded || send_signal(&writer, signal).is_err() {
+                        // Makes relaying the output of the command fail.
+                        writer.lock().unwrap().shutdown(Shutdown::Both).ok();
+                        return Some(signal);
+                    }
+                    debug!(signal:?; "forwarded signal to launched command");
+                    forwarded = true;
+                }
+            }
+        });
+
+        Ok(Self {
+            old_mask,
+            stopping,
+            thread,
+        })
+    }
+
+    /// Stops forwarding signals and unblocks them again. Returns the signal
+    /// the command was given up on for, if any.
+    fn stop(self) -> Option<Signal> {
+        self.stopping.store(true, Ordering::Relaxed);
+        // Wakes up the thread, unless it's gone already. The signal is blocked
+        // there and so only ever reaches its `sigwait()`.
+        pthread_kill(self.thread.as_pthread_t(), Signal::SIGTERM).ok();
+        let signal = self.thread.join().unwrap();
+        if let Err(err) = self.old_mask.thread_set_mask() {
+            debug!(err:%; "failed to restore signal mask");
+        }
+        signal
+    }
+}
+
+fn send_signal<W: Write>(writer: &Mutex<W>, signal: Signal) -> io::Result<()> {
+    let mut writer = writer.lock().unwrap();
+    write_frame(
+        &mut *writer,
+        FrameKind::Signal,
+        &(signal as i32).to_be_bytes(),
+    )
+}
+
+/// Tells the server we're still there every [`HEARTBEAT_INTERVAL`], with a
+/// signal 0 frame, until the connection breaks.
+fn send_heartbeats<W: Write>(writer: &Mutex<W>) -> io::Result<()> {
+    loop {
+        thread::sleep(HEARTBEAT_INTERVAL);
+        let mut writer = writer.lock().unwrap();
+        write_frame(&mut *writer, FrameKind::Signal, &0i32.to_be_bytes())?;
+    }
+}
+
+/// Sends everything read from `stdin` to the launched command, followed by an
+/// empty frame once `stdin` is exhausted.
+fn forward_stdin<R: Read, W: Write>(stdin: &mut R, writer: &Mutex<W>) -> io::Result<()> {
+    let mut buf = vec![0u8; 8192];
+    loop {
+        let len = match stdin.read(&mut buf) {
+            Ok(len) => len,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let mut writer = writer.lock().unwrap();
+        write_frame(&mut *writer, FrameKind::Stdin, &buf[..len])?;
+        if len == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn parse_response(resp: &str) -> Result<(), LaunchError> {
+    if resp.trim_end() == "OK" {
+        return Ok(());
+    }
+    let detailed = resp
+        .strip_prefix(ERROR_RESPONSE_PREFIX)
+        .and_then(|json| serde_json::from_str::<ErrorResponse>(json).ok());
+    match detailed {
+        Some(response) => Err(LaunchError::Server(response.to_string())),
+        None => Err(LaunchError::Server(resp.trim().to_owned())),
+    }
+}
+
+/// Writes the output of the launched command to `stdout` and `stderr` as it
+/// arrives from the server, until the server reports its exit code.
+fn relay_output<R, O, E>(reader: &mut R, stdout: &mut O, stderr: &mut E) -> Result<i32, LaunchError>
+where
+    R: Read,
+    O: Write,
+    E: Write,
+{
+    relay_output_to(reader, |stream, data| {
+        let output: &mut dyn Write = match stream {
+            OutputStream::Stdout => &mut *stdout,
+            OutputStream::Stderr => &mut *stderr,
+        };
+        // Failing to write our own output (e.g. stdout was closed) should not
+        // stop us from getting the exit code.
+        output.write_all(data).and_then(|_| output.flush()).ok();
+    })
+}
+
+/// Passes the output of the launched command to `on_output` as it arrives from
+/// the server, until the server reports its exit code.
+fn relay_output_to<R, F>(reader: &mut R, mut on_output: F) -> Result<i32, LaunchError>
+where
+    R: Read,
+    F: FnMut(OutputStream, &[u8]),
+{
+    let mut buf = Vec::new();
+    let mut timed_out = false;
+    loop {
+        let kind = read_frame(reader, &mut buf)
+            .map_err(|err| LaunchError::Server(format!("failed to read command output: {err}")))?
+            .map_err(|kind| LaunchError::Server(format!("invalid frame kind {kind}")))?;
+
+        match kind {
+            FrameKind::Stdout => on_output(OutputStream::Stdout, &buf),
+            FrameKind::Stderr => on_output(OutputStream::Stderr, &buf),
+            FrameKind::ExitCode => {
+                let exit_code =
+                    buf.as_slice()
+                        .try_into()
+                        .map(i32::from_be_bytes)
+                        .map_err(|_| {
+                            LaunchError::Server(format!("invalid exit code payload {buf:?}"))
+                        })?;
+                if timed_out {
+                    debug!(exit_code; "launched command timed out");
+                    return Ok(TIMED_OUT_EXIT_CODE);
+                }
+                return Ok(exit_code);
+            },
+            FrameKind::TimedOut => timed_out = true,
+            FrameKind::Stdin | FrameKind::Signal | FrameKind::Request | FrameKind::LaunchId => {
+                return Err(LaunchError::Server(format!(
+                    "unexpected frame kind {kind:?} from server"
+                )));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+    use std::net::{Ipv6Addr, TcpListener};
+    use std::os::fd::{AsRawFd as _, IntoRawFd as _};
+    use std::os::unix::ffi::OsStrExt as _;
+    use std::os::unix::fs::MetadataExt as _;
+    use std::sync::mpsc;
+
+    use rustix::fs::{mknodat, FileType, Mode, CWD};
+    use rustix::pipe::pipe;
+    use utils::launch::frame_header;
+
+    use super::*;
+    use crate::env::tests::ENV_LOCK;
+
+    fn read_request_frame<R: Read>(reader: &mut R) -> Request {
+        let mut buf = vec![];
+        let kind = read_frame(reader, &mut buf).unwrap();
+        assert_eq!(kind, Ok(FrameKind::Request));
+        serde_json::from_slice(&buf).unwrap()
+    }
+
+    /// A launch of `command` in `/` with nothing else set, for tests to
+    /// change what they need with struct update syntax.
+    fn test_launch(command: &str) -> Launch {
+        Launch {
+            command: PathBuf::from(command),
+            command_args: vec![],
+            env: HashMap::new(),
+            cwd: PathBuf::from("/"),
+            forward_stdin: false,
+            timeout_ms: None,
+            user: None,
+            detach: false,
+            argv0: None,
+            fail_if_busy: false,
+            clean_env: false,
+            stdin: vec![],
+            kill_on_disconnect: false,
+            idle_timeout_ms: None,
+            capabilities: None,
+            umask: None,
+            stdout_path: None,
+            stderr_path: None,
+            append_output: false,
+            merge_stderr: false,
+        }
+    }
+
+    #[test]
+    fn send_request_with_eom_in_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let launch = Launch {
+            command_args: vec!["EOM".to_owned()],
+            env: HashMap::from([("PAYLOAD".to_owned(), "\nEOM\n".to_owned())]),
+            cwd: env::current_dir().unwrap(),
+            ..test_launch("env")
+        };
+        let request = Request::Launch(launch);
+        send_request(addr, &request, CONNECT_TIMEOUT).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!(read_request_frame(&mut stream), request);
+    }
+
+    #[test]
+    fn parse_ok_response() {
+        assert!(parse_response("OK\n").is_ok());
+        assert!(parse_response("OK").is_ok());
+    }
+
+    #[test]
+    fn parse_error_response() {
+        let err = parse_response("Failed to execute \"foo\"\n").unwrap_err();
+        assert!(
+            matches!(err, LaunchError::Server(ref resp) if resp == r#"Failed to execute "foo""#)
+        );
+        assert!(matches!(parse_response(""), Err(LaunchError::Server(_))));
+    }
+
+    #[test]
+    fn parse_detailed_error_response() {
+        let resp = concat!(
+            r#"ERROR {"message":"Failed to execute \"foo\" as child process: "#,
+            r#"No such file or directory (os error 2)","errno":2}"#,
+            "\n"
+        );
+        let err = parse_response(resp).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "krun server returned an error: Failed to execute \"foo\" as child process: No such \
+             file or directory (os error 2) [errno 2]"
+        );
+
+        let err = parse_response("ERROR {\"message\":\"oops\"}\n").unwrap_err();
+        assert_eq!(err.to_string(), "krun server returned an error: oops");
+        // Not JSON after all, so shown as is.
+        let err = parse_response("ERROR oops\n").unwrap_err();
+        assert!(matches!(err, LaunchError::Server(ref resp) if resp == "ERROR oops"));
+    }
+
+    #[test]
+    fn relay_stdout_and_stderr() {
+        let mut frames = vec![];
+        for (kind, payload) in [
+            (FrameKind::Stdout, &b"out"[..]),
+            (FrameKind::Stderr, &b"err"[..]),
+            (FrameKind::Stdout, &b"put"[..]),
+            (FrameKind::ExitCode, &137i32.to_be_bytes()[..]),
+        ] {
+            frames.extend(frame_header(kind, payload.len() as u32));
+            frames.extend(payload);
+        }
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+
+        let exit_code = relay_output(&mut frames.as_slice(), &mut stdout, &mut stderr).unwrap();
+        assert_eq!(exit_code, 137);
+        assert_eq!(stdout, b"output");
+        assert_eq!(stderr, b"err");
+    }
+
+    #[test]
+    fn stream_launch_output() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request_frame(&mut stream);
+            stream.write_all(b"OK\n").unwrap();
+            for (kind, payload) in [
+                (FrameKind::Stdout, &b"hel"[..]),
+                (FrameKind::Stdout, &b"lo\n"[..]),
+                (FrameKind::Stderr, &b"oops\n"[..]),
+                (FrameKind::ExitCode, &3i32.to_be_bytes()[..]),
+            ] {
+                stream
+                    .write_all(&frame_header(kind, payload.len() as u32))
+                    .unwrap();
+                stream.write_all(payload).unwrap();
+                thread::sleep(Duration::from_millis(10));
+            }
+            request
+        });
+
+        let mut chunks = vec![];
+        let exit_code = stream_launch(
+            port.into(),
+            PathBuf::from("echo"),
+            vec!["hello".to_owned()],
+            vec![],
+            LaunchOptions::default(),
+            |stream, data| chunks.push((stream, data.to_vec())),
+        )
+        .unwrap();
+        assert_eq!(exit_code, 3);
+        assert_eq!(
+            chunks,
+            [
+                (OutputStream::Stdout, b"hel".to_vec()),
+                (OutputStream::Stdout, b"lo\n".to_vec()),
+                (OutputStream::Stderr, b"oops\n".to_vec()),
+            ]
+        );
+        let Request::Launch(launch) = server.join().unwrap() else {
+            panic!("expected a launch request");
+        };
+        assert_eq!(launch.command_args, ["hello"]);
+        assert!(!launch.forward_stdin);
+        assert!(!launch.kill_on_disconnect && launch.idle_timeout_ms.is_none());
+    }
+
+    #[test]
+    fn relay_timed_out() {
+        let mut frames = frame_header(FrameKind::TimedOut, 0).to_vec();
+        frames.extend(frame_header(FrameKind::ExitCode, 4));
+        frames.extend(143i32.to_be_bytes());
+        let exit_code = relay_output(&mut frames.as_slice(), &mut vec![], &mut vec![]).unwrap();
+        assert_eq!(exit_code, TIMED_OUT_EXIT_CODE);
+    }
+
+    #[test]
+    fn relay_without_exit_code() {
+        let mut frames = frame_header(FrameKind::Stdout, 3).to_vec();
+        frames.extend(b"out");
+        let err = relay_output(&mut frames.as_slice(), &mut vec![], &mut vec![]).unwrap_err();
+        assert!(matches!(err, LaunchError::Server(_)));
+    }
+
+    #[test]
+    fn forward_stdin_frames() {
+        let writer = Mutex::new(Vec::new());
+        forward_stdin(&mut &b"hello"[..], &writer).unwrap();
+        let mut expected = frame_header(FrameKind::Stdin, 5).to_vec();
+        expected.extend_from_slice(b"hello");
+        expected.extend_from_slice(&frame_header(FrameKind::Stdin, 0));
+        assert_eq!(writer.into_inner().unwrap(), expected);
+    }
+
+    #[test]
+    fn send_interrupt_signal() {
+        let writer = Mutex::new(Vec::new());
+        send_signal(&writer, Signal::SIGINT).unwrap();
+        let mut expected = frame_header(FrameKind::Signal, 4).to_vec();
+        expected.extend_from_slice(&2i32.to_be_bytes());
+        assert_eq!(writer.into_inner().unwrap(), expected);
+    }
+
+    #[test]
+    fn forward_signals_until_stopped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connect = || {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            (Arc::new(Mutex::new(client)), listener.accept().unwrap().0)
+        };
+        let mask = SigSet::thread_get_mask().unwrap();
+
+        let (client, mut server) = connect();
+        let forwarder = SignalForwarder::start(client).unwrap();
+        assert!(SigSet::thread_get_mask().unwrap().contains(Signal::SIGINT));
+        pthread_kill(forwarder.thread.as_pthread_t(), Signal::SIGINT).unwrap();
+        let mut buf = vec![];
+        assert_eq!(
+            read_frame(&mut server, &mut buf).unwrap(),
+            Ok(FrameKind::Signal)
+        );
+        assert_eq!(buf, 2i32.to_be_bytes());
+        assert_eq!(forwarder.stop(), None);
+        assert_eq!(SigSet::thread_get_mask().unwrap(), mask);
+
+        // A second signal gives up on the command.
+        let (client, mut server) = connect();
+        let forwarder = SignalForwarder::start(client).unwrap();
+        pthread_kill(forwarder.thread.as_pthread_t(), Signal::SIGTERM).unwrap();
+        assert_eq!(
+            read_frame(&mut server, &mut buf).unwrap(),
+            Ok(FrameKind::Signal)
+        );
+        pthread_kill(forwarder.thread.as_pthread_t(), Signal::SIGTERM).unwrap();
+        assert_eq!(server.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(forwarder.stop(), Some(Signal::SIGTERM));
+        assert_eq!(SigSet::thread_get_mask().unwrap(), mask);
+    }
+
+    #[test]
+    fn parse_server_port_values() {
+        assert_eq!(parse_server_port("3334").unwrap(), 3334);
+        for port in ["", "abc", "-1", "1024", "65536"] {
+            let err = parse_server_port(port).unwrap_err();
+            assert!(err.to_string().contains("KRUN_SERVER_PORT"));
+        }
+    }
+
+    #[test]
+    fn detect_terminals() {
+        let (reader, writer) = pipe().unwrap();
+        let null = File::open("/dev/null").unwrap();
+        assert!(!all_terminals(&[reader.as_fd(), writer.as_fd()]));
+        assert!(!all_terminals(&[null.as_fd()]));
+        assert!(all_terminals(&[]));
+        // Only where the tests run with a controlling terminal.
+        if let Ok(tty) = File::options().read(true).write(true).open("/dev/tty") {
+            assert!(all_terminals(&[tty.as_fd()]));
+            assert!(!all_terminals(&[tty.as_fd(), null.as_fd()]));
+        }
+    }
+
+    #[test]
+    fn read_port_from_pipe() {
+        let (reader, writer) = pipe().unwrap();
+        let mut writer = File::from(writer);
+        writer.write_all(b"4000\n").unwrap();
+        // The server may keep its end open after writing the port.
+        assert_eq!(
+            read_port_fd(&reader.into_raw_fd().to_string()).unwrap(),
+            4000
+        );
+
+        let (reader, writer) = pipe().unwrap();
+        drop(writer);
+        let err = read_port_fd(&reader.into_raw_fd().to_string()).unwrap_err();
+        assert!(err.to_string().contains("was closed before"));
+
+        let (reader, writer) = pipe().unwrap();
+        File::from(writer).write_all(b"80\n").unwrap();
+        let err = read_port_fd(&reader.into_raw_fd().to_string()).unwrap_err();
+        assert!(err.to_string().contains(r#"Invalid server port "80""#));
+
+        for fd in ["", "-1", "0", "1", "2", "stdin"] {
+            assert!(read_port_fd(fd).is_err(), "{fd:?}");
+        }
+
+        // Fds opened by this process are close-on-exec, and aren't taken over.
+        let file = File::open("/dev/null").unwrap();
+        let err = read_port_fd(&file.as_raw_fd().to_string()).unwrap_err();
+        assert!(err.to_string().contains("close-on-exec"));
+        assert!(fcntl_getfd(&file).is_ok());
+    }
+
+    #[test]
+    fn write_port_to_pipe() {
+        let (reader, writer) = pipe().unwrap();
+        write_port_fd(writer.into_raw_fd(), 4000).unwrap();
+        // The write end is closed once the port is written.
+        let mut port = String::new();
+        File::from(reader).read_to_string(&mut port).unwrap();
+        assert_eq!(port, "4000\n");
+
+        assert!(write_port_fd(2, 4000).is_err());
+    }
+
+    #[test]
+    fn lock_instances_side_by_side() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            instance_lock_path(dir.path(), None).unwrap(),
+            dir.path().join("krun.lock")
+        );
+        for instance in ["", "a/b"] {
+            assert!(instance_lock_path(dir.path(), Some(instance)).is_err());
+        }
+
+        let foo = instance_lock_path(dir.path(), Some("foo")).unwrap();
+        let bar = instance_lock_path(dir.path(), Some("bar")).unwrap();
+        assert_ne!(foo, bar);
+        let (foo_lock, _) = lock_file_at(&foo, 3334).unwrap();
+        let (bar_lock, _) = lock_file_at(&bar, 3335).unwrap();
+        assert_eq!(foo_lock.unwrap().1, LockReason::FirstStart);
+        assert_eq!(bar_lock.unwrap().1, LockReason::FirstStart);
+    }
+
+    #[test]
+    fn lock_held_but_server_dead() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("krun.lock");
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        // Held by "another" krun instance whose server is gone.
+        let mut owner = File::create(&lock_path).unwrap();
+        flock(&owner, FlockOperation::NonBlockingLockExclusive).unwrap();
+        owner.write_all(format!("{port}").as_bytes()).unwrap();
+
+        let err = lock_file_at(&lock_path, 3334).unwrap_err();
+        assert!(err.to_string().contains("not responding"));
+    }
+
+    #[test]
+    fn lock_held_with_server_alive() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("krun.lock");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut owner = File::create(&lock_path).unwrap();
+        flock(&owner, FlockOperation::NonBlockingLockExclusive).unwrap();
+        owner.write_all(format!("{port}").as_bytes()).unwrap();
+
+        let (lock_file, running_port) = lock_file_at(&lock_path, 3334).unwrap();
+        assert!(lock_file.is_none());
+        assert_eq!(running_port, Some(port.into()));
+    }
+
+    #[test]
+    fn lock_held_while_server_starts() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("krun.lock");
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut owner = File::create(&lock_path).unwrap();
+        flock(&owner, FlockOperation::NonBlockingLockExclusive).unwrap();
+        owner
+            .write_all(format!("1234 {port}\n").as_bytes())
+            .unwrap();
+        // The server only starts listening a little after its port is written.
+        let server = thread::spawn(move || {
+            thread::sleep(LIVENESS_RETRY_INTERVAL * 2);
+            let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+            listener.accept().unwrap();
+        });
+
+        let (lock_file, running_port) = lock_file_at(&lock_path, 3334).unwrap();
+        server.join().unwrap();
+        assert!(lock_file.is_none());
+        assert_eq!(running_port, Some(port.into()));
+    }
+
+    #[test]
+    fn lock_held_while_port_is_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("krun.lock");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // The owner has taken the lock, but only written part of its line yet.
+        let mut owner = File::create(&lock_path).unwrap();
+        flock(&owner, FlockOperation::NonBlockingLockExclusive).unwrap();
+        owner.write_all(b"1234 ").unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(LOCK_READ_INTERVAL * 2);
+            owner.write_all(format!("{port}\n").as_bytes()).unwrap();
+            owner
+        });
+
+        let (lock_file, running_port) = lock_file_at(&lock_path, 3334).unwrap();
+        let _owner = writer.join().unwrap();
+        assert!(lock_file.is_none());
+        assert_eq!(running_port, Some(port.into()));
+    }
+
+    #[test]
+    fn take_over_stale_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("krun.lock");
+        fs::write(&lock_path, "4000").unwrap();
+
+        let (lock_file, running_port) = lock_file_at(&lock_path, 3334).unwrap();
+        assert_eq!(lock_file.unwrap().1, LockReason::TookOverStale);
+        assert_eq!(running_port, None);
+        assert_eq!(
+            fs::read_to_string(&lock_path).unwrap(),
+            format!("{} 3334\n", process::id())
+        );
+    }
+
+    #[test]
+    fn replace_lock_path_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("krun.lock");
+        fs::create_dir(&lock_path).unwrap();
+
+        // Nothing in the way of the lock file could have been a lock.
+        let (lock_file, running_port) = lock_file_at(&lock_path, 3334).unwrap();
+        assert_eq!(lock_file.unwrap().1, LockReason::FirstStart);
+        assert_eq!(running_port, None);
+        assert!(lock_path.is_file());
+
+        let lock_path = dir.path().join("krun-full.lock");
+        fs::create_dir_all(lock_path.join("leftover")).unwrap();
+        let err = lock_file_at(&lock_path, 3334).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Lock path {lock_path:?} is a directory that can't be removed")
+        );
+    }
+
+    #[test]
+    fn replace_lock_path_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("krun.lock");
+        mknodat(CWD, &lock_path, FileType::Fifo, Mode::RUSR | Mode::WUSR, 0).unwrap();
+
+        // Opening the fifo would block, as nobody has it open for writing.
+        let (lock_file, running_port) = lock_file_at(&lock_path, 3334).unwrap();
+        assert!(lock_file.is_some());
+        assert_eq!(running_port, None);
+        assert_eq!(
+            fs::read_to_string(&lock_path).unwrap(),
+            format!("{} 3334\n", process::id())
+        );
+    }
+
+    #[test]
+    fn server_status_from_lock_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("krun.lock");
+        assert_eq!(server_status_at(&lock_path).unwrap(), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        fs::write(&lock_path, format!("1234 {port}\n")).unwrap();
+        assert_eq!(server_status_at(&lock_path).unwrap(), Some(port.into()));
+
+        drop(listener);
+        assert_eq!(server_status_at(&lock_path).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_lock_data_formats() {
+        assert_eq!(parse_lock_data("1234 3334\n"), Some((Some(1234), 3334)));
+        assert_eq!(parse_lock_data("3334"), Some((None, 3334)));
+        assert_eq!(parse_lock_data("3334\n"), Some((None, 3334)));
+        // Still being written.
+        assert_eq!(parse_lock_data("1234 3334"), None);
+        assert_eq!(parse_lock_data("1234 "), None);
+        assert_eq!(parse_lock_data("1234 80\n"), None);
+        assert_eq!(parse_lock_data("1234 70000\n"), None);
+        assert_eq!(parse_lock_data("pid 3334"), None);
+        assert_eq!(parse_lock_data(""), None);
+    }
+
+    #[test]
+    fn launch_error_from_launch_or_lock() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        env::set_var("KRUN_SERVER_PORT", port.to_string());
+        let err = launch_or_lock(
+            3334,
+            PathBuf::from("true"),
+            vec![],
+            vec![],
+            None,
+            LaunchOptions::default(),
+        )
+        .err()
+        .unwrap();
+        env::remove_var("KRUN_SERVER_PORT");
+        assert!(matches!(
+            err.downcast_ref::<LaunchError>(),
+            Some(&LaunchError::Connection(_))
+        ));
+        assert!(format!("{err:#}")
+            .starts_with("could not request launch to server: could not connect to krun server"));
+        assert_eq!(
+            err.downcast_ref::<LaunchFailure>(),
+            Some(&LaunchFailure {
+                port: port.into(),
+                retries: 0
+            })
+        );
+    }
+
+    #[test]
+    fn limit_launch_request_size() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let mut launch = Launch {
+            env: HashMap::from([("KRUN_TEST".to_owned(), "x".repeat(1024))]),
+            detach: true,
+            ..test_launch("true")
+        };
+        assert!(launch_payload(&launch).unwrap().len() < 2048);
+
+        env::set_var("KRUN_MAX_REQUEST_SIZE", "2048");
+        launch_payload(&launch).unwrap();
+        launch.env.insert("KRUN_TEST".to_owned(), "x".repeat(2048));
+        // Fails before trying to connect to the server, which isn't there.
+        let err = send_launch(addr, &launch, CONNECT_TIMEOUT, RESPONSE_TIMEOUT).unwrap_err();
+        env::remove_var("KRUN_MAX_REQUEST_SIZE");
+        assert!(err.downcast_ref::<LaunchError>().is_none());
+        assert!(err
+            .to_string()
+            .contains("over the limit of 2048 bytes (see `KRUN_MAX_REQUEST_SIZE`)"));
+        assert!(parse_max_request_len(&(MAX_REQUEST_LEN + 1).to_string()).is_err());
+        assert!(parse_max_request_len("big").is_err());
+    }
+
+    #[test]
+    fn limit_connect_retries() {
+        assert_eq!(parse_connect_retries("0").unwrap(), 0);
+        assert_eq!(parse_connect_retries("10").unwrap(), 10);
+        for retries in ["", "-1", "many", "101"] {
+            let err = parse_connect_retries(retries).unwrap_err();
+            assert!(
+                err.to_string().contains("KRUN_CONNECT_RETRIES"),
+                "{retries:?}"
+            );
+        }
+
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let launch = Launch {
+            detach: true,
+            ..test_launch("true")
+        };
+        for (max_retries, message) in [
+            (0, "gave up after 1 attempt"),
+            (5, "gave up after 6 attempts"),
+        ] {
+            let err = request_launch_with_retries(
+                addr,
+                &launch,
+                CONNECT_TIMEOUT,
+                max_retries,
+                None,
+                false,
+            )
+            .unwrap_err();
+            assert!(format!("{err:#}").starts_with(&format!(
+                "could not request launch to server: {message}: could not connect"
+            )));
+            assert_eq!(
+                err.downcast_ref::<LaunchFailure>()
+                    .map(|failure| failure.retries),
+                Some(max_retries)
+            );
+        }
+    }
+
+    #[test]
+    fn launch_error_json_shape() {
+        let failure = LaunchFailure {
+            port: 3334,
+            retries: 3,
+        };
+        for (err, kind) in [
+            (
+                LaunchError::Connection(io::ErrorKind::ConnectionRefused.into()),
+                "connection",
+            ),
+            (LaunchError::Server("boom".to_owned()), "server"),
+            (LaunchError::Timeout(Duration::from_secs(2)), "timeout"),
+            (
+                LaunchError::ResponseTimeout(Duration::from_secs(30)),
+                "timeout",
+            ),
+        ] {
+            let message = format!("could not request launch to server: {err}");
+            let err = anyhow::Error::new(err).context(failure);
+            assert_eq!(
+                launch_error_json(&err),
+                serde_json::json!({
+                    "error": message,
+                    "kind": kind,
+                    "port": 3334,
+                    "retries": 3,
+                })
+            );
+        }
+
+        let err = anyhow!("No command to launch");
+        assert_eq!(
+            launch_error_json(&err),
+            serde_json::json!({
+                "error": "No command to launch",
+                "kind": null,
+                "port": null,
+                "retries": null,
+            })
+        );
+    }
+
+    #[test]
+    fn refuse_capabilities_with_old_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request_frame(&mut stream);
+            stream
+                .write_all(b"{\"protocol_version\":3,\"uptime_secs\":42}\n")
+                .unwrap();
+            request
+        });
+        let launch = Launch {
+            detach: true,
+            capabilities: Some(Capabilities::Drop(vec!["NET_RAW".to_owned()])),
+            ..test_launch("ping")
+        };
+
+        let err = send_launch(addr, &launch, CONNECT_TIMEOUT, RESPONSE_TIMEOUT).unwrap_err();
+        // Only the ping was sent, not the launch.
+        assert_eq!(server.join().unwrap(), Request::Ping);
+        assert_eq!(
+            err.to_string(),
+            "krun server returned an error: protocol version 3 is too old to restrict \
+             capabilities, 4 is needed"
+        );
+    }
+
+    #[test]
+    fn ping_mock_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request_frame(&mut stream);
+            stream
+                .write_all(b"{\"protocol_version\":1,\"uptime_secs\":42}\n")
+                .unwrap();
+            request
+        });
+
+        let info = ping(port.into()).unwrap();
+        assert_eq!(server.join().unwrap(), Request::Ping);
+        assert_eq!(
+            info,
+            ServerInfo {
+                protocol_version: 1,
+                uptime_secs: 42
+            }
+        );
+    }
+
+    #[test]
+    fn request_launch_batch_on_mock_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request_frame(&mut stream);
+            stream
+                .write_all(b"[{\"exited\":{\"exit_code\":1}},\"skipped\"]\n")
+                .unwrap();
+            request
+        });
+        let launches = vec![test_launch("false"), test_launch("true")];
+
+        let statuses =
+            request_launch_batch(port.into(), launches.clone(), BatchPolicy::StopOnFailure)
+                .unwrap();
+        assert_eq!(
+            statuses,
+            [BatchStatus::Exited { exit_code: 1 }, BatchStatus::Skipped]
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            Request::LaunchBatch {
+                launches,
+                policy: BatchPolicy::StopOnFailure,
+            }
+        );
+    }
+
+    #[test]
+    fn list_and_kill_launches_on_mock_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for resp in [
+                r#"[{"id":1,"command":"sleep","command_args":["60"],"started_secs":1700000000}]"#,
+                "OK\n",
+                r#"ERROR {"message":"No running launch with id 2"}"#,
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                requests.push(read_request_frame(&mut stream));
+                stream.write_all(resp.as_bytes()).unwrap();
+                stream.write_all(b"\n").unwrap();
+            }
+            requests
+        });
+
+        let launches = list_launches(port.into()).unwrap();
+        assert_eq!(
+            launches,
+            [LaunchInfo {
+                id: 1,
+                command: PathBuf::from("sleep"),
+                command_args: vec!["60".to_owned()],
+                started_secs: 1_700_000_000,
+            }]
+        );
+        kill_launch(port.into(), 1).unwrap();
+        let err = kill_launch(port.into(), 2).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "krun server returned an error: No running launch with id 2"
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                Request::ListLaunches,
+                Request::KillLaunch { id: 1 },
+                Request::KillLaunch { id: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_server_host_values() {
+        assert_eq!(
+            parse_server_host("10.0.2.2").unwrap(),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 2, 2))
+        );
+        assert_eq!(
+            parse_server_host("::1").unwrap(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+        assert_eq!(
+            parse_server_host("[fd00::2]").unwrap(),
+            "fd00::2".parse::<IpAddr>().unwrap()
+        );
+        for host in ["localhost", "10.0.2", "[::1", "::1]"] {
+            let err = parse_server_host(host).unwrap_err();
+            assert!(err.to_string().contains("KRUN_SERVER_HOST"));
+        }
+    }
+
+    #[test]
+    fn send_request_over_ipv4_and_ipv6() {
+        for host in ["127.0.0.1", "::1"] {
+            let host: IpAddr = host.parse().unwrap();
+            let listener = TcpListener::bind((host, 0)).unwrap();
+            let addr = server_addr(host, listener.local_addr().unwrap().port().into()).unwrap();
+            assert_eq!(addr.is_ipv6(), host.is_ipv6());
+
+            send_request(addr, &Request::Ping, CONNECT_TIMEOUT).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_request_frame(&mut stream), Request::Ping);
+        }
+        assert!(server_addr(DEFAULT_SERVER_HOST, 65536).is_err());
+    }
+
+    #[test]
+    fn refuse_to_run_inside_vm() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        env::set_var(INSIDE_VM_ENV_VAR, "1");
+        let err = launch_or_lock(
+            3334,
+            PathBuf::from("true"),
+            vec![],
+            vec![],
+            None,
+            LaunchOptions::default(),
+        )
+        .err()
+        .unwrap();
+        env::remove_var(INSIDE_VM_ENV_VAR);
+        assert_eq!(
+            err.to_string(),
+            "already inside a krun VM, run the command directly instead"
+        );
+    }
+
+    #[test]
+    fn dump_sent_launch_request() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let dump = dir.path().join("launch.json");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; FRAME_HEADER_LEN];
+            stream.read_exact(&mut header).unwrap();
+            let mut payload = vec![0u8; parse_frame_header(&header).1 as usize];
+            stream.read_exact(&mut payload).unwrap();
+            stream.write_all(b"OK\n").unwrap();
+            stream
+                .write_all(&frame_header(FrameKind::LaunchId, 8))
+                .unwrap();
+            stream.write_all(&5u64.to_be_bytes()).unwrap();
+            payload
+        });
+        let launch = Launch {
+            env: HashMap::from([("API_TOKEN".to_owned(), "hunter2".to_owned())]),
+            detach: true,
+            ..test_launch("true")
+        };
+
+        env::remove_var("KRUN_SENSITIVE_ENV_VARS");
+        env::set_var("KRUN_DEBUG_DUMP", &dump);
+        let result = request_launch(
+            addr,
+            &launch,
+            CONNECT_TIMEOUT,
+            RESPONSE_TIMEOUT,
+            None,
+            false,
+        );
+        env::remove_var("KRUN_DEBUG_DUMP");
+        assert!(matches!(result.unwrap(), LaunchResult::Detached { id: 5 }));
+
+        // The server gets the value, the dump doesn't.
+        let sent: Request = serde_json::from_slice(&server.join().unwrap()).unwrap();
+        let Request::Launch(sent) = sent else {
+            panic!("unexpected request {sent:?}");
+        };
+        assert_eq!(sent.env["API_TOKEN"], "hunter2");
+        let mut redacted = launch.clone();
+        redacted
+            .env
+            .insert("API_TOKEN".to_owned(), "***".to_owned());
+        let mut expected = format!("# krun launch request to {addr} over tcp\n").into_bytes();
+        expected.extend(serde_json::to_vec(&Request::Launch(redacted)).unwrap());
+        assert_eq!(fs::read(&dump).unwrap(), expected);
+        assert_eq!(fs::metadata(&dump).unwrap().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn refuse_to_dump_through_symlink() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let dump = dir.path().join("launch.json");
+        std::os::unix::fs::symlink(&target, &dump).unwrap();
+        let launch = Launch {
+            detach: true,
+            ..test_launch("true")
+        };
+
+        dump_request(&dump, "127.0.0.1:1".parse().unwrap(), &launch);
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn request_launch_over_ipv6_loopback() {
+        let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request_frame(&mut stream);
+            stream.write_all(b"OK\n").unwrap();
+            stream
+                .write_all(&frame_header(FrameKind::LaunchId, 8))
+                .unwrap();
+            stream.write_all(&3u64.to_be_bytes()).unwrap();
+            request
+        });
+        let launch = Launch {
+            detach: true,
+            ..test_launch("true")
+        };
+        let addr = server_addr(DEFAULT_SERVER_HOST, port.into()).unwrap();
+        let result = request_launch(
+            addr,
+            &launch,
+            CONNECT_TIMEOUT,
+            RESPONSE_TIMEOUT,
+            None,
+            false,
+        )
+        .unwrap();
+        assert!(matches!(result, LaunchResult::Detached { id: 3 }));
+        assert_eq!(server.join().unwrap(), Request::Launch(launch));
+    }
+
+    #[test]
+    fn request_launch_closed_port() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let launch = Launch {
+            cwd: env::current_dir().unwrap(),
+            ..test_launch("true")
+        };
+        let addr = server_addr(DEFAULT_SERVER_HOST, port.into()).unwrap();
+        let err = request_launch(
+            addr,
+            &launch,
+            CONNECT_TIMEOUT,
+            RESPONSE_TIMEOUT,
+            None,
+            false,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LaunchError>(),
+            Some(&LaunchError::Connection(_))
+        ));
+    }
+
+    #[test]
+    fn no_retry_after_request_sent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            // Drop the connection without answering, as a server going away
+            // would, possibly after starting the command.
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request_frame(&mut stream);
+            drop(stream);
+            (listener, request)
+        });
+        let launch = Launch {
+            detach: true,
+            ..test_launch("true")
+        };
+        let err = request_launch_with_retries(
+            addr,
+            &launch,
+            CONNECT_TIMEOUT,
+            CONNECT_RETRIES,
+            None,
+            false,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LaunchError>(),
+            Some(&LaunchError::Connection(_))
+        ));
+        assert_eq!(
+            err.downcast_ref::<LaunchFailure>()
+                .map(|failure| failure.retries),
+            Some(0)
+        );
+        let (listener, request) = server.join().unwrap();
+        assert_eq!(request, Request::Launch(launch));
+        // The launch wasn't requested again.
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    type LogRecord = (String, HashMap<String, String>);
+
+    /// Collects the message and key-values of the log records of threads
+    /// inside [`capture_logs`].
+    struct CapturingLogger;
+
+    thread_local! {
+        static LOGS: RefCell<Option<Vec<LogRecord>>> = const { RefCell::new(None) };
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            LOGS.with_borrow(Option::is_some)
+        }
+
+        fn log(&self, record: &log::Record) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            struct Collect(HashMap<String, String>);
+            impl<'kvs> log::kv::VisitSource<'kvs> for Collect {
+                fn visit_pair(
+                    &mut self,
+                    key: log::kv::Key<'kvs>,
+                    value: log::kv::Value<'kvs>,
+                ) -> Result<(), log::kv::Error> {
+                    self.0.insert(key.to_string(), value.to_string());
+                    Ok(())
+                }
+            }
+            let mut kvs = Collect(HashMap::new());
+            record.key_values().visit(&mut kvs).unwrap();
+            LOGS.with_borrow_mut(|logs| {
+                if let Some(logs) = logs {
+                    logs.push((record.args().to_string(), kvs.0));
+                }
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Runs `f` and returns what it logged on the current thread. Logging is
+    /// only enabled meanwhile, so other tests don't pay for it.
+    fn capture_logs(f: impl FnOnce()) -> Vec<LogRecord> {
+        static INIT_LOGGER: std::sync::Once = std::sync::Once::new();
+        INIT_LOGGER.call_once(|| log::set_logger(&CapturingLogger).unwrap());
+        LOGS.with_borrow_mut(|logs| *logs = Some(vec![]));
+        log::set_max_level(log::LevelFilter::Trace);
+        f();
+        log::set_max_level(log::LevelFilter::Off);
+        LOGS.with_borrow_mut(Option::take).unwrap()
+    }
+
+    #[test]
+    fn log_launch_lifecycle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request_frame(&mut stream);
+            stream.write_all(b"OK\n").unwrap();
+            stream
+                .write_all(&frame_header(FrameKind::LaunchId, 8))
+                .unwrap();
+            stream.write_all(&6u64.to_be_bytes()).unwrap();
+        });
+        let launch = Launch {
+            detach: true,
+            ..test_launch("true")
+        };
+        let logs = capture_logs(|| {
+            request_launch_with_retries(
+                addr,
+                &launch,
+                CONNECT_TIMEOUT,
+                CONNECT_RETRIES,
+                None,
+                false,
+            )
+            .unwrap();
+        });
+        server.join().unwrap();
+
+        let port = addr.port().to_string();
+        assert!(logs.iter().all(|(_, kvs)| kvs["port"] == port));
+        let messages: Vec<_> = logs.iter().map(|(msg, _)| msg.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "connected to server",
+                "sent request",
+                "server accepted launch request",
+            ]
+        );
+        assert!(logs
+            .iter()
+            .all(|(_, kvs)| kvs["elapsed_ms"].parse::<u64>().is_ok()));
+    }
+
+    #[test]
+    fn cancel_launch_before_connecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = Arc::new(AtomicBool::new(true));
+        let launch = Launch {
+            detach: true,
+            ..test_launch("true")
+        };
+
+        let started = Instant::now();
+        let err = request_launch_with_retries(
+            addr,
+            &launch,
+            CONNECT_TIMEOUT,
+            CONNECT_RETRIES,
+            Some(&cancel),
+            false,
+        )
+        .unwrap_err();
+        assert!(started.elapsed() < CONNECT_TIMEOUT);
+        assert!(matches!(
+            err.downcast_ref::<LaunchError>(),
+            Some(&LaunchError::Cancelled)
+        ));
+        assert_eq!(
+            err.downcast_ref::<LaunchFailure>()
+                .map(|failure| failure.retries),
+            Some(0)
+        );
+        // The server wasn't even connected to.
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn cancel_queued_launch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request_frame(&mut stream);
+            stream.write_all(b"QUEUED\n").unwrap();
+            // Wait for the client to go away, no slot ever coming free.
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest).unwrap();
+        });
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = Arc::clone(&cancel);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                cancel.store(true, Ordering::Relaxed);
+            })
+        };
+        let launch = Launch {
+            detach: true,
+            ..test_launch("true")
+        };
+
+        let started = Instant::now();
+        let err =
+            request_launch_with_retries(addr, &launch, CONNECT_TIMEOUT, 0, Some(&cancel), false)
+                .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(matches!(
+            err.downcast_ref::<LaunchError>(),
+            Some(&LaunchError::Cancelled)
+        ));
+        canceller.join().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn request_launch_stalled_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accept the connection, but never answer.
+        let server = thread::spawn(move || listener.accept().unwrap());
+        let launch = Launch {
+            cwd: env::current_dir().unwrap(),
+            ..test_launch("true")
+        };
+        let addr = server_addr(DEFAULT_SERVER_HOST, port.into()).unwrap();
+        let timeout = Duration::from_millis(100);
+        let err = request_launch(addr, &launch, CONNECT_TIMEOUT, timeout, None, false).unwrap_err();
+        let _stream = server.join().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<LaunchError>(),
+            Some(&LaunchError::ResponseTimeout(t)) if t == timeout
+        ));
+    }
+
+    #[test]
+    fn check_command_path() {
+        let dir = tempfile::tempdir().unwrap();
+        File::create(dir.path().join("run.sh")).unwrap();
+
+        for command in ["krun-test-missing", "run.sh", "./run.sh"] {
+            check_command(Path::new(command), Some(dir.path()), true).unwrap();
+        }
+        check_command(&dir.path().join("run.sh"), None, true).unwrap();
+
+        for command in ["./krun-test-missing", "/krun-test-missing/run.sh"] {
+            check_command(Path::new(command), Some(dir.path()), false).unwrap();
+            let err = check_command(Path::new(command), Some(dir.path()), true).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Command {command:?} does not exist")
+            );
+        }
+    }
+
+    #[test]
+    fn reject_unencodable_command() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let command = PathBuf::from(OsStr::from_bytes(b"/usr/bin/caf\xe9"));
+        let err = check_command_encoding(&command, &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Failed to process command "/usr/bin/caf\xE9" as it contains invalid UTF-8"#
+        );
+        let err = launch_or_lock(
+            DEFAULT_SERVER_PORT,
+            command,
+            vec![],
+            vec![],
+            None,
+            LaunchOptions::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid UTF-8"));
+
+        let err = check_command_encoding(Path::new("/bin/a\0b"), &[]).unwrap_err();
+        assert!(err.to_string().contains("NUL character"));
+        let err = check_command_encoding(Path::new("/bin/sh"), &["-c\0".to_owned()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Failed to process argument "-c\0" as it contains NUL character"#
+        );
+        check_command_encoding(Path::new("/bin/sh"), &["-c".to_owned(), "café".to_owned()])
+            .unwrap();
+    }
+
+    #[test]
+    fn forward_umask() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let previous = umask(Mode::from_raw_mode(0o027));
+        let launch = prepare_launch(
+            PathBuf::from("true"),
+            vec![],
+            vec![],
+            LaunchOptions::default(),
+        );
+        let options = LaunchOptions {
+            server_umask: true,
+            ..Default::default()
+        };
+        let server_umask_launch = prepare_launch(PathBuf::from("true"), vec![], vec![], options);
+        umask(previous);
+        assert_eq!(launch.unwrap().umask, Some(0o027));
+        assert_eq!(server_umask_launch.unwrap().umask, None);
+    }
+
+    #[test]
+    fn default_argv0_to_base_name() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let launch = prepare_launch(
+            PathBuf::from("/usr/bin/busybox"),
+            vec![],
+            vec![],
+            LaunchOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(launch.argv0.as_deref(), Some("busybox"));
+
+        let options = LaunchOptions {
+            argv0: Some("-sh".to_owned()),
+            ..Default::default()
+        };
+        let launch = prepare_launch(PathBuf::from("/bin/sh"), vec![], vec![], options).unwrap();
+        assert_eq!(launch.argv0.as_deref(), Some("-sh"));
+        assert_eq!(launch.command, Path::new("/bin/sh"));
+    }
+
+    #[test]
+    fn parse_umask_from_status() {
+        let status = "Name:\tkrun\nUmask:\t0027\nState:\tR (running)\n";
+        assert_eq!(parse_status_umask(status), Some(0o027));
+        assert_eq!(parse_status_umask("Name:\tkrun\n"), None);
+        assert_eq!(parse_status_umask("Umask:\t0089\n"), None);
+    }
+
+    #[test]
+    fn limit_stdin_buffer() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let options = LaunchOptions {
+            stdin: b"config".to_vec(),
+            ..Default::default()
+        };
+        let launch = prepare_launch(PathBuf::from("apply"), vec![], vec![], options).unwrap();
+        assert_eq!(launch.stdin, b"config");
+        assert!(!launch.forward_stdin);
+
+        let options = LaunchOptions {
+            stdin: vec![0; MAX_STDIN_LEN + 1],
+            ..Default::default()
+        };
+        let err = prepare_launch(PathBuf::from("apply"), vec![], vec![], options).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "stdin of {} bytes is over the limit of {MAX_STDIN_LEN} bytes, forward it as a \
+                 stream instead",
+                MAX_STDIN_LEN + 1
+            )
+        );
+    }
+
+    /// A connection to a server that answers with `input`, keeping what it's
+    /// sent in `output`.
+    struct FakeStream {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn request_launch_on_fake_stream() {
+        let launch = Launch {
+            command_args: vec!["60".to_owned()],
+            detach: true,
+            ..test_launch("sleep")
+        };
+        let mut input = b"QUEUED\nOK\n".to_vec();
+        input.extend(frame_header(FrameKind::LaunchId, 8));
+        input.extend(7u64.to_be_bytes());
+        let mut stream = FakeStream {
+            input: io::Cursor::new(input),
+            output: vec![],
+        };
+
+        let result = request_launch_on(&mut stream, &launch).unwrap();
+        assert!(matches!(result, LaunchResult::Detached { id: 7 }));
+        assert_eq!(
+            read_request_frame(&mut stream.output.as_slice()),
+            Request::Launch(launch.clone())
+        );
+
+        let mut stream = FakeStream {
+            input: io::Cursor::new(b"No such\nlaunch".to_vec()),
+            output: vec![],
+        };
+        let err = request_launch_on(&mut stream, &launch).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LaunchError>(),
+            Some(LaunchError::Server(resp)) if resp == "No such\nlaunch"
+        ));
+
+        let mut stream = FakeStream {
+            input: io::Cursor::new(vec![]),
+            output: vec![],
+        };
+        let err = request_launch_on(&mut stream, &launch).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LaunchError>().unwrap().kind(),
+            "connection"
+        );
+    }
+
+    #[test]
+    fn request_detached_launch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (done_tx, done_rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request_frame(&mut stream);
+            stream.write_all(b"OK\n").unwrap();
+            stream
+                .write_all(&frame_header(FrameKind::LaunchId, 8))
+                .unwrap();
+            stream.write_all(&7u64.to_be_bytes()).unwrap();
+            // Keep the connection open, as if the command were still running.
+            done_rx.recv().unwrap();
+            request
+        });
+        let launch = Launch {
+            detach: true,
+            ..test_launch("daemon")
+        };
+        let addr = server_addr(DEFAULT_SERVER_HOST, port.into()).unwrap();
+        let result = request_launch(
+            addr,
+            &launch,
+            CONNECT_TIMEOUT,
+            RESPONSE_TIMEOUT,
+            None,
+            false,
+        )
+        .unwrap();
+        done_tx.send(()).unwrap();
+        assert!(matches!(result, LaunchResult::Detached { id: 7 }));
+        assert_eq!(server.join().unwrap(), Request::Launch(launch));
+    }
+
+    #[test]
+    fn wait_for_queued_launch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let timeout = Duration::from_millis(100);
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request_frame(&mut stream);
+            stream.write_all(b"QUEUED\n").unwrap();
+            // Admitting a queued launch may take longer than any response.
+            thread::sleep(timeout * 3);
+            stream.write_all(b"OK\n").unwrap();
+            stream
+                .write_all(&frame_header(FrameKind::LaunchId, 8))
+                .unwrap();
+            stream.write_all(&1u64.to_be_bytes()).unwrap();
+        });
+        let launch = Launch {
+            detach: true,
+            ..test_launch("make")
+        };
+        let addr = server_addr(DEFAULT_SERVER_HOST, port.into()).unwrap();
+        let result = request_launch(addr, &launch, CONNECT_TIMEOUT, timeout, None, false).unwrap();
+        server.join().unwrap();
+        assert!(matches!(result, LaunchResult::Detached { id: 1 }));
     }
 }
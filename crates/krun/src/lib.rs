@@ -3,4 +3,5 @@ pub mod cpu;
 pub mod env;
 pub mod launch;
 pub mod net;
+pub mod self_test;
 pub mod types;
@@ -0,0 +1,306 @@
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use rustix::fs::{access, Access};
+use serde::Serialize;
+use utils::env::{find_in_path, runtime_dir};
+
+use crate::launch::{
+    connect_timeout, lock_path, lock_server_addr, parse_lock_data, request_server_info,
+    server_override_addr,
+};
+
+/// Device through which libkrun runs the microVM. The vsock devices of the
+/// microVM are emulated by libkrun itself, so this is all it needs from the
+/// host kernel.
+const KVM_PATH: &str = "/dev/kvm";
+
+/// Outcome of one of the checks of [`self_test`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// The check doesn't apply, e.g. there's no running server to reach.
+    Skipped,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct SelfTestCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// What was found, e.g. the path of a file that was checked.
+    pub detail: String,
+    /// How to fix a failed check.
+    pub hint: Option<&'static str>,
+}
+
+impl SelfTestCheck {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Fail,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn skipped(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Skipped,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct SelfTestReport {
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    /// Whether none of the checks failed.
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != CheckStatus::Fail)
+    }
+}
+
+impl Display for SelfTestReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Pass => "ok",
+                CheckStatus::Fail => "FAIL",
+                CheckStatus::Skipped => "skipped",
+            };
+            writeln!(f, "{}: {status} ({})", check.name, check.detail)?;
+            if let Some(hint) = check.hint {
+                writeln!(f, "  {hint}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks what launching a command needs, for troubleshooting: `socat` on
+/// the host, the runtime dir, the lock file, the server of the running krun
+/// instance if any, and KVM. Every check is run, whether or not the ones
+/// before it failed.
+pub fn self_test() -> Result<SelfTestReport> {
+    let lock_path = lock_path()?;
+    Ok(SelfTestReport {
+        checks: vec![
+            check_socat(),
+            check_runtime_dir(),
+            check_lock_file(&lock_path),
+            check_server(&lock_path, connect_timeout()?),
+            check_kvm(Path::new(KVM_PATH)),
+        ],
+    })
+}
+
+fn check_socat() -> SelfTestCheck {
+    const NAME: &str = "socat";
+    match find_in_path("socat") {
+        Ok(Some(path)) => SelfTestCheck::pass(NAME, format!("{path:?}")),
+        Ok(None) => SelfTestCheck::fail(
+            NAME,
+            "not found in PATH",
+            "Install socat, which forwards the X11 display and other sockets to the microVM",
+        ),
+        Err(err) => SelfTestCheck::fail(
+            NAME,
+            format!("{err:#}"),
+            "Make sure PATH is set, socat is looked up there",
+        ),
+    }
+}
+
+fn check_runtime_dir() -> SelfTestCheck {
+    const NAME: &str = "runtime dir";
+    let xdg_runtime_dir = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+    match runtime_dir() {
+        Ok(dir) if Some(&dir) == xdg_runtime_dir.as_ref() => {
+            SelfTestCheck::pass(NAME, format!("{dir:?}"))
+        },
+        Ok(dir) => SelfTestCheck::pass(
+            NAME,
+            format!("{dir:?}, as XDG_RUNTIME_DIR is unset or not writable"),
+        ),
+        Err(err) => SelfTestCheck::fail(
+            NAME,
+            format!("{err:#}"),
+            "Set XDG_RUNTIME_DIR to a directory only you can write to",
+        ),
+    }
+}
+
+fn check_lock_file(lock_path: &Path) -> SelfTestCheck {
+    const NAME: &str = "lock file";
+    const HINT: &str = "Remove the lock file or fix its permissions, or point KRUN_LOCK_PATH \
+                        elsewhere";
+    match lock_path.symlink_metadata() {
+        Ok(metadata) if metadata.is_file() => {
+            match File::options().read(true).write(true).open(lock_path) {
+                Ok(_) => SelfTestCheck::pass(NAME, format!("{lock_path:?}")),
+                Err(err) => SelfTestCheck::fail(NAME, format!("{lock_path:?}: {err}"), HINT),
+            }
+        },
+        Ok(_) => SelfTestCheck::fail(NAME, format!("{lock_path:?} is not a regular file"), HINT),
+        Err(_) => {
+            let dir = lock_path.parent().unwrap_or(Path::new("/"));
+            match access(dir, Access::WRITE_OK | Access::EXEC_OK) {
+                Ok(()) => SelfTestCheck::pass(NAME, format!("{lock_path:?} can be created")),
+                Err(err) => SelfTestCheck::fail(
+                    NAME,
+                    format!("{lock_path:?} can't be created in {dir:?}: {err}"),
+                    HINT,
+                ),
+            }
+        },
+    }
+}
+
+/// Pings the server that [`launch_or_lock`](crate::launch::launch_or_lock)
+/// would launch on: the one in `KRUN_SERVER_PORT` if set, otherwise the one
+/// whose port is recorded in the lock file.
+fn check_server(lock_path: &Path, connect_timeout: Duration) -> SelfTestCheck {
+    const NAME: &str = "server";
+    let addr = match server_override_addr() {
+        Ok(Some(addr)) => addr,
+        Ok(None) => match lock_file_server_addr(lock_path) {
+            Ok(addr) => addr,
+            Err(check) => return check,
+        },
+        Err(err) => {
+            return SelfTestCheck::fail(
+                NAME,
+                format!("{err:#}"),
+                "Fix or unset KRUN_SERVER_PORT and KRUN_SERVER_HOST",
+            )
+        },
+    };
+    match request_server_info(addr, connect_timeout) {
+        Ok(info) => SelfTestCheck::pass(
+            NAME,
+            format!(
+                "{addr}, protocol version {}, up for {}s",
+                info.protocol_version, info.uptime_secs
+            ),
+        ),
+        Err(err) => SelfTestCheck::fail(
+            NAME,
+            format!("{addr}: {err}"),
+            "Check KRUN_SERVER_HOST, or stop the running krun instance and start it again",
+        ),
+    }
+}
+
+/// Returns the address of the server whose port is recorded in the lock file,
+/// or why [`check_server`] is skipped.
+fn lock_file_server_addr(lock_path: &Path) -> Result<SocketAddr, SelfTestCheck> {
+    const NAME: &str = "server";
+    let port = match fs::read_to_string(lock_path) {
+        Ok(data) => parse_lock_data(&data).map(|(_pid, port)| port),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => {
+            return Err(SelfTestCheck::skipped(
+                NAME,
+                format!("unknown whether krun is running: {err}"),
+            ))
+        },
+    };
+    let Some(port) = port else {
+        return Err(SelfTestCheck::skipped(NAME, "no krun instance is running"));
+    };
+    lock_server_addr(port).map_err(|err| SelfTestCheck::skipped(NAME, format!("{err}")))
+}
+
+fn check_kvm(kvm_path: &Path) -> SelfTestCheck {
+    const NAME: &str = "kvm";
+    match File::options().read(true).write(true).open(kvm_path) {
+        Ok(_) => SelfTestCheck::pass(NAME, format!("{kvm_path:?}")),
+        Err(err) => SelfTestCheck::fail(
+            NAME,
+            format!("{kvm_path:?}: {err}"),
+            "Make sure KVM is enabled and that you are in the group owning the device, usually \
+             `kvm`",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::env::tests::{EnvGuard, ENV_LOCK};
+
+    #[test]
+    fn self_test_failures() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let _env = EnvGuard::new(&["KRUN_SERVER_PORT", "KRUN_SERVER_HOST"]);
+        env::remove_var("KRUN_SERVER_PORT");
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("krun.lock");
+        assert_eq!(check_lock_file(&lock_path).status, CheckStatus::Pass);
+        let check = check_lock_file(&dir.path().join("missing/krun.lock"));
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.hint.is_some());
+
+        // The server is recorded in the lock file, but gone.
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let check = check_server(&lock_path, Duration::from_secs(1));
+        assert_eq!(check.status, CheckStatus::Skipped);
+        fs::write(&lock_path, format!("1234 {}\n", addr.port())).unwrap();
+        let check = check_server(&lock_path, Duration::from_secs(1));
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.starts_with(&addr.to_string()));
+
+        // `KRUN_SERVER_PORT` takes precedence over the lock file, as when
+        // launching.
+        let missing_lock_path = dir.path().join("missing.lock");
+        env::set_var("KRUN_SERVER_PORT", addr.port().to_string());
+        env::set_var("KRUN_SERVER_HOST", "::1");
+        let check = check_server(&missing_lock_path, Duration::from_secs(1));
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.starts_with(&format!("[::1]:{}", addr.port())));
+        env::set_var("KRUN_SERVER_PORT", "80");
+        let check = check_server(&missing_lock_path, Duration::from_secs(1));
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.contains("KRUN_SERVER_PORT"));
+
+        let check = check_kvm(&dir.path().join("kvm"));
+        assert_eq!(check.status, CheckStatus::Fail);
+
+        let report = SelfTestReport {
+            checks: vec![check_lock_file(&lock_path), check],
+        };
+        assert!(!report.passed());
+        assert!(report
+            .to_string()
+            .starts_with(&format!("lock file: ok ({lock_path:?})\nkvm: FAIL (")));
+    }
+}
@@ -0,0 +1,88 @@
+//! Standard base64, with padding, for byte buffers carried in JSON. Meant to
+//! be used as `#[serde(with = "crate::base64")]`.
+//!
+//! This is all the protocol needs, and small enough to keep here rather than
+//! add the `base64` crate to what krun, krun-server and krun-guest are built
+//! from, which distributions package and review crate by crate.
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serializer};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub fn decode(encoded: &str) -> Result<Vec<u8>, String> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 4 != 0 {
+        return Err(format!("invalid base64 length {}", encoded.len()));
+    }
+    let mut bytes = Vec::with_capacity(encoded.len() / 4 * 3);
+    let chunks = encoded.len() / 4;
+    for (index, chunk) in encoded.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && index + 1 != chunks) {
+            return Err("invalid base64 padding".to_owned());
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = ALPHABET
+                .iter()
+                .position(|&a| a == c)
+                .ok_or_else(|| format!("invalid base64 character {:?}", c as char))?;
+            n = n << 6 | value as u32;
+        }
+        n <<= 6 * padding;
+        bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(bytes)
+}
+
+pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    decode(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trip() {
+        let cases: [(&[u8], &str); 5] = [
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"\xff\x00\xfe\x01", "/wD+AQ=="),
+        ];
+        for (bytes, encoded) in cases {
+            assert_eq!(encode(bytes), encoded);
+            assert_eq!(decode(encoded).unwrap(), bytes);
+        }
+        assert!(decode("Zm9").is_err());
+        assert!(decode("Zg==Zm9v").is_err());
+        assert!(decode("Zm9*").is_err());
+    }
+}
@@ -1,10 +1,24 @@
 use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt as _, MetadataExt as _};
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
+use rustix::fs::{access, Access};
+use rustix::process::getuid;
 
 use crate::fs::find_executable;
 
+/// Lists the host sockets forwarded to the microVM with `--forward-socket`,
+/// as comma-separated `VAR:PORT` entries, where `VAR` is the env var holding
+/// the path of the socket and `PORT` the vsock port it's reachable at.
+pub const FORWARDED_SOCKETS_ENV_VAR: &str = "KRUN_FORWARDED_SOCKETS";
+
+/// vsock port of the first socket forwarded with `--forward-socket`, the next
+/// ones using the following ports.
+pub const FORWARDED_SOCKET_BASE_PORT: u32 = 7000;
+
 pub fn find_in_path<P>(program: P) -> Result<Option<PathBuf>>
 where
     P: AsRef<Path>,
@@ -21,8 +35,17 @@ where
         return Err(anyhow!("`PATH` env var is not set or invalid"));
     };
 
-    for search_path in env::split_paths(&path_env) {
-        let path = search_path.join(program);
+    find_in_dirs(program, env::split_paths(&path_env))
+}
+
+/// Returns the first executable `program` in `dirs`.
+fn find_in_dirs<I>(program: &Path, dirs: I) -> Result<Option<PathBuf>>
+where
+    I: IntoIterator,
+    I::Item: AsRef<Path>,
+{
+    for dir in dirs {
+        let path = dir.as_ref().join(program);
         if let Some(path) = find_executable(&path)
             .with_context(|| format!("Failed to check existence of {path:?}"))?
         {
@@ -32,3 +55,141 @@ where
 
     Ok(None)
 }
+
+/// Returns the directory for runtime files such as the krun lock file:
+/// `XDG_RUNTIME_DIR` if it is a writable directory, otherwise `/tmp/krun-$UID`,
+/// created with 0700 permissions if needed. `XDG_RUNTIME_DIR` may be unset or
+/// stale without a user session, e.g. under cron or ssh.
+pub fn runtime_dir() -> Result<PathBuf> {
+    runtime_dir_from(
+        env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from),
+        Path::new("/tmp"),
+    )
+}
+
+fn runtime_dir_from(xdg_runtime_dir: Option<PathBuf>, tmp_dir: &Path) -> Result<PathBuf> {
+    if let Some(dir) = xdg_runtime_dir {
+        if dir.is_absolute()
+            && dir.is_dir()
+            && access(&dir, Access::WRITE_OK | Access::EXEC_OK).is_ok()
+        {
+            return Ok(dir);
+        }
+    }
+
+    let uid = getuid().as_raw();
+    let dir = tmp_dir.join(format!("krun-{uid}"));
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+            return Err(err).with_context(|| format!("Failed to create runtime dir {dir:?}"));
+        },
+        _ => {},
+    }
+    // Anyone can create it in `/tmp`, so make sure it is ours alone.
+    let metadata = fs::symlink_metadata(&dir)
+        .with_context(|| format!("Failed to get metadata of runtime dir {dir:?}"))?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(anyhow!(
+            "runtime dir {dir:?} is not a private directory of the current user"
+        ));
+    }
+
+    Ok(dir)
+}
+
+/// Parses the value of [`FORWARDED_SOCKETS_ENV_VAR`] into env var names and
+/// vsock ports.
+pub fn parse_forwarded_sockets(value: &str) -> Result<Vec<(String, u32)>> {
+    value
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (var, port) = entry
+                .split_once(':')
+                .filter(|(var, _)| !var.is_empty())
+                .with_context(|| format!("Invalid forwarded socket {entry:?}"))?;
+            let port = port
+                .parse()
+                .with_context(|| format!("Invalid vsock port in forwarded socket {entry:?}"))?;
+            Ok((var.to_owned(), port))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::{symlink, PermissionsExt as _};
+
+    use super::*;
+
+    #[test]
+    fn parse_forwarded_socket_list() {
+        assert_eq!(
+            parse_forwarded_sockets("SSH_AUTH_SOCK:7000,GPG_AGENT_SOCK:7001").unwrap(),
+            [
+                ("SSH_AUTH_SOCK".to_owned(), 7000),
+                ("GPG_AGENT_SOCK".to_owned(), 7001)
+            ]
+        );
+        assert!(parse_forwarded_sockets("").unwrap().is_empty());
+        for value in ["SSH_AUTH_SOCK", ":7000", "SSH_AUTH_SOCK:ssh"] {
+            assert!(parse_forwarded_sockets(value).is_err(), "{value:?}");
+        }
+    }
+
+    #[test]
+    fn skip_broken_symlink_in_path() {
+        let broken_dir = tempfile::tempdir().unwrap();
+        symlink(
+            broken_dir.path().join("missing"),
+            broken_dir.path().join("krun-guest"),
+        )
+        .unwrap();
+        let not_executable_dir = tempfile::tempdir().unwrap();
+        fs::write(not_executable_dir.path().join("krun-guest"), "").unwrap();
+        let valid_dir = tempfile::tempdir().unwrap();
+        let valid_path = valid_dir.path().join("krun-guest");
+        fs::write(&valid_path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&valid_path, Permissions::from_mode(0o755)).unwrap();
+
+        let dirs = [
+            broken_dir.path(),
+            not_executable_dir.path(),
+            valid_dir.path(),
+        ];
+        assert_eq!(
+            find_in_dirs(Path::new("krun-guest"), dirs).unwrap(),
+            Some(valid_path.canonicalize().unwrap())
+        );
+        assert_eq!(find_in_dirs(Path::new("krun-server"), dirs).unwrap(), None);
+    }
+
+    #[test]
+    fn fall_back_to_private_runtime_dir() {
+        let xdg_runtime_dir = tempfile::tempdir().unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fallback = tmp_dir.path().join(format!("krun-{}", getuid().as_raw()));
+
+        assert_eq!(
+            runtime_dir_from(Some(xdg_runtime_dir.path().to_owned()), tmp_dir.path()).unwrap(),
+            xdg_runtime_dir.path()
+        );
+        for xdg_runtime_dir in [
+            None,
+            Some(PathBuf::new()),
+            Some(xdg_runtime_dir.path().join("missing")),
+        ] {
+            assert_eq!(
+                runtime_dir_from(xdg_runtime_dir, tmp_dir.path()).unwrap(),
+                fallback
+            );
+        }
+        let metadata = fs::metadata(&fallback).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.mode() & 0o777, 0o700);
+
+        fs::set_permissions(&fallback, Permissions::from_mode(0o755)).unwrap();
+        assert!(runtime_dir_from(None, tmp_dir.path()).is_err());
+    }
+}
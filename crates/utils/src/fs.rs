@@ -1,8 +1,7 @@
-use std::fs;
-use std::os::unix::fs::PermissionsExt as _;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
+use rustix::fs::{access, Access};
 
 pub fn find_executable<P>(path: P) -> Result<Option<PathBuf>>
 where
@@ -10,18 +9,19 @@ where
 {
     let path = path.as_ref();
 
+    // Resolve symlinks first, so a broken symlink is treated as missing and
+    // the checks below apply to the file that would actually be executed.
+    let Ok(path) = path.canonicalize() else {
+        return Ok(None);
+    };
+
     if !path.is_file() {
         return Ok(None);
     }
 
-    let Ok(metadata) = fs::metadata(path) else {
+    if access(&path, Access::EXEC_OK).is_err() {
         return Ok(None);
-    };
-
-    if metadata.permissions().mode() & 0o111 != 0 {
-        let path = path.canonicalize().context("Failed to canonicalize path")?;
-        return Ok(Some(path));
     }
 
-    Ok(None)
+    Ok(Some(path))
 }
@@ -1,11 +1,491 @@
 use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
+/// Version of the protocol spoken between krun and krun-server, to be bumped on
+/// incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// First protocol version whose servers apply [`Launch::capabilities`]. Older
+/// ones ignore it, so clients have to check before relying on it.
+pub const CAPABILITIES_PROTOCOL_VERSION: u32 = 4;
+
+/// Largest [`Launch::stdin`] the server accepts. Larger inputs are to be
+/// streamed with [`Launch::forward_stdin`] instead.
+pub const MAX_STDIN_LEN: usize = 64 * 1024;
+
+/// Largest [`FrameKind::Request`] payload the server accepts, in bytes.
+pub const MAX_REQUEST_LEN: u32 = 16 * 1024 * 1024;
+
+/// Largest [`FrameKind::Stdin`] payload the server accepts, in bytes. Longer
+/// input is to be sent in several frames.
+pub const MAX_STDIN_FRAME_LEN: u32 = 64 * 1024;
+
+/// A request sent to the server as JSON in a [`FrameKind::Request`] frame.
+/// Older clients send a bare [`Launch`] as JSON followed by `\nEOM\n` instead.
+// A request is handled once per connection, which makes boxing the launch not
+// worth it.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+    Launch(Launch),
+    /// Asks the server for its [`ServerInfo`], sent back as a line of JSON.
+    Ping,
+    /// Asks the server for the launches whose command is still running, sent
+    /// back as a line of JSON with a list of [`LaunchInfo`].
+    ListLaunches,
+    /// Asks the server to terminate the command of a launch, as it does on
+    /// timeout. The server answers `OK` or an error message.
+    KillLaunch {
+        id: u64,
+    },
+    /// Asks the server to run the commands of `launches` one after the other,
+    /// as detached launches whose output is logged to a file. Once they're
+    /// done, the server answers a line of JSON with the [`BatchStatus`] of
+    /// each, in order.
+    LaunchBatch {
+        launches: Vec<Launch>,
+        policy: BatchPolicy,
+    },
+}
+
+/// Which Linux capabilities the command of a launch may have, by name with or
+/// without the `CAP_` prefix, e.g. `NET_ADMIN`.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capabilities {
+    /// Drop these, keeping the others.
+    Drop(Vec<String>),
+    /// Keep only these, dropping all the others.
+    Keep(Vec<String>),
+}
+
+/// What the server does with the rest of a batch after a command of it failed.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchPolicy {
+    /// Skip the remaining commands.
+    StopOnFailure,
+    /// Run the remaining commands anyway.
+    Continue,
+}
+
+/// How a command of a batch went. It failed unless it exited with code 0.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    /// The command exited, with `128 + signal` as exit code if it was killed
+    /// by a signal.
+    Exited { exit_code: i32 },
+    /// The command was killed for running past its timeout.
+    TimedOut,
+    /// The command could not be started.
+    Failed { error: String },
+    /// The command was not run, as an earlier one failed.
+    Skipped,
+}
+
+impl BatchStatus {
+    pub fn success(&self) -> bool {
+        matches!(self, Self::Exited { exit_code: 0 })
+    }
+}
+
+/// How often the client of a launch with [`Launch::idle_timeout_ms`] set tells
+/// the server it's still there.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Starts the line the server answers a launch request with when it fails to
+/// start the command, followed by an [`ErrorResponse`] as JSON. Other errors
+/// are answered with a plain message.
+pub const ERROR_RESPONSE_PREFIX: &str = "ERROR ";
+
+/// Why the server failed to start the command of a launch.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    /// The error and its causes, e.g. `Failed to execute "foo" as child
+    /// process: No such file or directory (os error 2)`.
+    pub message: String,
+    /// The OS error number behind the failure, if any, as seen in the guest.
+    #[serde(default)]
+    pub errno: Option<i32>,
+}
+
+impl Display for ErrorResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(errno) = self.errno {
+            write!(f, " [errno {errno}]")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+pub struct ServerInfo {
+    pub protocol_version: u32,
+    pub uptime_secs: u64,
+}
+
+/// A launch whose command is running in the server.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+pub struct LaunchInfo {
+    /// Identifies the launch in a [`Request::KillLaunch`].
+    pub id: u64,
+    pub command: PathBuf,
+    pub command_args: Vec<String>,
+    /// When the command was started, in seconds since the Unix epoch.
+    pub started_secs: u64,
+}
+
 #[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
 pub struct Launch {
     pub command: PathBuf,
     pub command_args: Vec<String>,
     pub env: HashMap<String, String>,
+    /// Working directory of the command. The oldest clients don't send it, in
+    /// which case the command runs in the home directory of its user.
+    #[serde(default)]
+    pub cwd: PathBuf,
+    /// Whether the client streams its stdin after the launch request.
+    #[serde(default)]
+    pub forward_stdin: bool,
+    /// Time after which the command is killed, in milliseconds.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Name or uid of the user to run the command as, instead of the one the
+    /// server runs as.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Whether to detach the command from the client. The server then answers
+    /// with a [`FrameKind::LaunchId`] frame right after accepting the request,
+    /// and logs the output of the command to a file instead of sending it.
+    #[serde(default)]
+    pub detach: bool,
+    /// What the command sees as `argv[0]`, instead of `command`.
+    #[serde(default)]
+    pub argv0: Option<String>,
+    /// Whether the server should refuse the launch rather than queue it when
+    /// it already runs as many launches as it allows. A queued launch is
+    /// answered with a `QUEUED` line, and then as usual once it's admitted.
+    #[serde(default)]
+    pub fail_if_busy: bool,
+    /// Whether the command starts from a clean environment, with only `env`
+    /// and the `PATH`, `HOME` and `TERM` of the server, rather than the whole
+    /// environment of the server with `env` on top.
+    #[serde(default)]
+    pub clean_env: bool,
+    /// Written to the stdin of the command, which is then closed, as base64 in
+    /// JSON. At most [`MAX_STDIN_LEN`] bytes, and only used when the stdin of
+    /// the client isn't forwarded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "crate::base64")]
+    pub stdin: Vec<u8>,
+    /// Whether the server kills the command, as for [`Request::KillLaunch`],
+    /// once the client closes or resets the connection, e.g. because it was
+    /// killed.
+    #[serde(default)]
+    pub kill_on_disconnect: bool,
+    /// If set, the client sends a [`FrameKind::Signal`] frame with signal 0
+    /// every [`HEARTBEAT_INTERVAL`] while the command runs, and the server
+    /// kills the command once the client sends nothing for this many
+    /// milliseconds, for a client that is gone but left the connection open.
+    /// A client that is merely suspended, e.g. with Ctrl-Z, stops sending them
+    /// too, so this is opt-in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_ms: Option<u64>,
+    /// Linux capabilities to take away from the command, including from its
+    /// bounding set so that it can't regain them by executing a setuid program.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+    /// File mode creation mask of the command, instead of the one of the
+    /// server, usually that of the client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub umask: Option<u32>,
+    /// File the stdout of the command is written to, e.g. `/dev/null`, instead
+    /// of being sent to the client or logged. A relative path is relative to
+    /// `cwd`. Servers predating it ignore it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_path: Option<PathBuf>,
+    /// File the stderr of the command is written to, as with `stdout_path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr_path: Option<PathBuf>,
+    /// Whether `stdout_path` and `stderr_path` are appended to rather than
+    /// truncated.
+    #[serde(default)]
+    pub append_output: bool,
+    /// Whether the stderr of the command goes wherever its stdout goes, as
+    /// with `2>&1`. Without `stdout_path`, it's sent to the client as stdout.
+    #[serde(default)]
+    pub merge_stderr: bool,
+}
+
+/// After accepting a launch request, the server sends the output of the
+/// command and finally its exit code as frames made of a 1-byte kind, a 4-byte
+/// big endian payload length and the payload itself. In the other direction,
+/// the client sends the stdin of the command and the signals it should receive
+/// as frames too. The connection identifies the launched command, so no
+/// process id is needed.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[repr(u8)]
+pub enum FrameKind {
+    Stdout = 1,
+    Stderr = 2,
+    /// The payload is the exit code as a 4-byte big endian `i32`.
+    ExitCode = 3,
+    /// An empty payload closes the stdin of the command.
+    Stdin = 4,
+    /// The payload is the signal number as a 4-byte big endian `i32`, to be
+    /// sent to the process group of the command. As with kill(2), signal 0 is
+    /// not sent, which makes it the heartbeat of [`Launch::idle_timeout_ms`] that
+    /// servers predating it ignore.
+    Signal = 5,
+    /// The payload is a [`Request`] as JSON. It is the first frame sent by the
+    /// client.
+    Request = 6,
+    /// Sent with an empty payload before the exit code if the command was
+    /// killed because it ran past its timeout.
+    TimedOut = 7,
+    /// The payload is the id of a detached launch as an 8-byte big endian
+    /// `u64`, sent instead of the output and the exit code.
+    LaunchId = 8,
+}
+
+pub const FRAME_HEADER_LEN: usize = 5;
+
+impl TryFrom<u8> for FrameKind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Stdout),
+            2 => Ok(Self::Stderr),
+            3 => Ok(Self::ExitCode),
+            4 => Ok(Self::Stdin),
+            5 => Ok(Self::Signal),
+            6 => Ok(Self::Request),
+            7 => Ok(Self::TimedOut),
+            8 => Ok(Self::LaunchId),
+            _ => Err(value),
+        }
+    }
+}
+
+pub fn frame_header(kind: FrameKind, len: u32) -> [u8; FRAME_HEADER_LEN] {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    header[0] = kind as u8;
+    header[1..].copy_from_slice(&len.to_be_bytes());
+    header
+}
+
+/// Returns the kind, or the unknown kind byte, and the payload length of a
+/// frame.
+pub fn parse_frame_header(header: &[u8; FRAME_HEADER_LEN]) -> (Result<FrameKind, u8>, u32) {
+    let len = u32::from_be_bytes(header[1..].try_into().expect("header should be 5 bytes"));
+    (FrameKind::try_from(header[0]), len)
+}
+
+/// Writes a frame of `kind` with `data` as payload, and flushes `writer`.
+pub fn write_frame<W: Write>(writer: &mut W, kind: FrameKind, data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len()).expect("frame payload should fit in u32");
+    writer.write_all(&frame_header(kind, len))?;
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+/// Reads a frame into `buf`, which is resized to its payload, and returns its
+/// kind, or the unknown kind byte.
+pub fn read_frame<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<Result<FrameKind, u8>> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let (kind, len) = parse_frame_header(&header);
+    buf.resize(len as usize, 0);
+    reader.read_exact(buf)?;
+    Ok(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn launch_round_trip() {
+        let launch = Launch {
+            command: PathBuf::from("ls"),
+            command_args: vec!["-l".to_owned()],
+            env: HashMap::from([("FOO".to_owned(), "bar".to_owned())]),
+            cwd: PathBuf::from("/home/user/project"),
+            forward_stdin: false,
+            timeout_ms: Some(30_000),
+            user: Some("alice".to_owned()),
+            detach: true,
+            argv0: Some("ls".to_owned()),
+            fail_if_busy: true,
+            clean_env: true,
+            stdin: b"key = value\n".to_vec(),
+            kill_on_disconnect: true,
+            idle_timeout_ms: Some(60_000),
+            capabilities: None,
+            umask: None,
+            stdout_path: Some(PathBuf::from("/var/log/ls.log")),
+            stderr_path: None,
+            append_output: true,
+            merge_stderr: true,
+        };
+        let json = serde_json::to_string(&launch).unwrap();
+        assert!(json.contains(r#""cwd":"/home/user/project""#));
+        assert!(json.contains(r#""stdin":"a2V5ID0gdmFsdWUK""#));
+        assert_eq!(serde_json::from_str::<Launch>(&json).unwrap(), launch);
+    }
+
+    #[test]
+    fn request_format() {
+        assert_eq!(serde_json::to_string(&Request::Ping).unwrap(), r#""ping""#);
+        assert_eq!(
+            serde_json::to_string(&Request::KillLaunch { id: 3 }).unwrap(),
+            r#"{"kill_launch":{"id":3}}"#
+        );
+        let json = r#"{"launch":{"command":"ls","command_args":[],"env":{},"cwd":"/","forward_stdin":true}}"#;
+        assert!(matches!(
+            serde_json::from_str::<Request>(json).unwrap(),
+            Request::Launch(Launch {
+                forward_stdin: true,
+                timeout_ms: None,
+                user: None,
+                detach: false,
+                argv0: None,
+                fail_if_busy: false,
+                clean_env: false,
+                ref stdin,
+                kill_on_disconnect: false,
+                idle_timeout_ms: None,
+                capabilities: None,
+                umask: None,
+                stdout_path: None,
+                stderr_path: None,
+                append_output: false,
+                merge_stderr: false,
+                ..
+            }) if stdin.is_empty()
+        ));
+    }
+
+    #[test]
+    fn batch_format() {
+        let request = Request::LaunchBatch {
+            launches: vec![],
+            policy: BatchPolicy::StopOnFailure,
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"launch_batch":{"launches":[],"policy":"stop_on_failure"}}"#
+        );
+        let statuses = vec![
+            BatchStatus::Exited { exit_code: 1 },
+            BatchStatus::TimedOut,
+            BatchStatus::Skipped,
+        ];
+        assert_eq!(
+            serde_json::to_string(&statuses).unwrap(),
+            r#"[{"exited":{"exit_code":1}},"timed_out","skipped"]"#
+        );
+        assert!(BatchStatus::Exited { exit_code: 0 }.success());
+        assert!(!statuses.iter().any(BatchStatus::success));
+    }
+
+    #[test]
+    fn request_frame_round_trip() {
+        let launch = Launch {
+            command: PathBuf::from("true"),
+            command_args: vec![],
+            env: HashMap::new(),
+            cwd: PathBuf::from("/"),
+            forward_stdin: false,
+            timeout_ms: None,
+            user: None,
+            detach: false,
+            argv0: None,
+            fail_if_busy: false,
+            clean_env: false,
+            stdin: vec![],
+            kill_on_disconnect: false,
+            idle_timeout_ms: None,
+            capabilities: None,
+            umask: None,
+            stdout_path: None,
+            stderr_path: None,
+            append_output: false,
+            merge_stderr: false,
+        };
+        let requests = [
+            Request::Launch(launch.clone()),
+            Request::Ping,
+            Request::ListLaunches,
+            Request::KillLaunch { id: 7 },
+            Request::LaunchBatch {
+                launches: vec![launch],
+                policy: BatchPolicy::Continue,
+            },
+        ];
+        let mut frames = vec![];
+        for request in &requests {
+            let json = serde_json::to_vec(request).unwrap();
+            write_frame(&mut frames, FrameKind::Request, &json).unwrap();
+        }
+
+        let mut reader = frames.as_slice();
+        let mut buf = vec![];
+        for request in requests {
+            let kind = read_frame(&mut reader, &mut buf).unwrap();
+            assert_eq!(kind, Ok(FrameKind::Request));
+            assert_eq!(serde_json::from_slice::<Request>(&buf).unwrap(), request);
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let frames = [
+            (FrameKind::Stdout, b"out".to_vec()),
+            (FrameKind::Stderr, b"err".to_vec()),
+            (FrameKind::Stdin, vec![]),
+            (FrameKind::Signal, 15i32.to_be_bytes().to_vec()),
+            (FrameKind::TimedOut, vec![]),
+            (FrameKind::LaunchId, 3u64.to_be_bytes().to_vec()),
+            (FrameKind::ExitCode, 124i32.to_be_bytes().to_vec()),
+        ];
+        let mut data = vec![];
+        for (kind, payload) in &frames {
+            write_frame(&mut data, *kind, payload).unwrap();
+        }
+
+        let mut reader = data.as_slice();
+        let mut buf = vec![];
+        for (kind, payload) in frames {
+            assert_eq!(read_frame(&mut reader, &mut buf).unwrap(), Ok(kind));
+            assert_eq!(buf, payload);
+        }
+        let err = read_frame(&mut reader, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            read_frame(&mut [9, 0, 0, 0, 0].as_slice(), &mut buf).unwrap(),
+            Err(9)
+        );
+    }
+
+    #[test]
+    fn frame_header_layout() {
+        assert_eq!(frame_header(FrameKind::Stderr, 258), [2, 0, 0, 1, 2]);
+        assert_eq!(FrameKind::try_from(2), Ok(FrameKind::Stderr));
+        assert_eq!(FrameKind::try_from(0), Err(0));
+        assert_eq!(
+            parse_frame_header(&frame_header(FrameKind::Signal, 4)),
+            (Ok(FrameKind::Signal), 4)
+        );
+        assert_eq!(parse_frame_header(&[9, 0, 0, 0, 0]), (Err(9), 0));
+    }
 }
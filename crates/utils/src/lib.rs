@@ -1,3 +1,4 @@
+mod base64;
 pub mod env;
 pub mod fs;
 pub mod launch;